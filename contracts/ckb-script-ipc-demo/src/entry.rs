@@ -1,115 +1,118 @@
-use alloc::{ffi::CString, format, string::String};
-use ckb_script_ipc_common::{channel::Channel, spawn::spawn_server};
-use ckb_std::{ckb_constants::Source, env::argv, high_level::inherited_fds, log::info, logger};
+use alloc::{format, string::String, vec, vec::Vec};
+use ckb_script_ipc_common::{
+    channel::Channel,
+    pipe::Pipe,
+    server::{run_server_with, server_config},
+    spawn::SpawnBuilder,
+};
+use ckb_std::{
+    ckb_constants::Source,
+    ckb_types::{bytes::Bytes, prelude::*},
+    env::argv,
+    high_level::load_script,
+    log::info,
+    logger,
+};
+use serde::{Deserialize, Serialize};
 
 use crate::error::Error;
 
-// before proc-macro expansion
-// #[derive(CkbScriptIpc)]
-// trait World {
-//     // note self is not used
-//     fn hello(name: String) -> Result<(), String>;
-// }
-// will be expanded to the following:
-
-// ---------------------------------
-// start of auto generated code
-// ---------------------------------
-trait World: Sized {
-    fn hello(&self, name: String) -> Result<String, u64>;
-
-    fn server(self) -> ServeWorld<Self> {
-        ServeWorld { service: self }
+const READ_CAPACITY: usize = 1024;
+
+// pipelined echo requests, with or without flow control
+const PIPELINED: u8 = 1;
+const PIPELINED_WITHOUT_FLOW_CONTROL: u8 = 2;
+const ECHO_COUNT: u8 = 4;
+const ECHO_SIZE: usize = 1024;
+
+// Script args select how channels read from pipes, so that cycles of buffered
+// and unbuffered reading can be compared in tests: when the first byte is 0,
+// every read is a syscall. The second byte selects a scenario run after
+// `hello`.
+fn script_args() -> Bytes {
+    match load_script() {
+        Ok(script) => script.args().unpack(),
+        Err(_) => Bytes::new(),
     }
 }
 
-struct ServeWorld<S> {
-    service: S,
-}
-
-impl<S> ckb_script_ipc_common::ipc::Serve for ServeWorld<S>
-where
-    S: World + Sized,
-{
-    type Req = WorldRequest;
-    type Resp = WorldResponse;
-    fn serve(
-        &mut self,
-        req: Self::Req,
-    ) -> Result<Self::Resp, ckb_script_ipc_common::error::IpcError> {
-        match req {
-            WorldRequest::Hello { name } => {
-                let ret = self.service.hello(name);
-                Ok(WorldResponse::Hello(ret))
-            }
-        }
+fn read_capacity(args: &Bytes) -> usize {
+    if args.first() == Some(&0) {
+        1
+    } else {
+        READ_CAPACITY
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-enum WorldRequest {
-    Hello { name: String },
+// passed from client to server in argv
+#[derive(Serialize, Deserialize)]
+struct DemoConfig {
+    read_capacity: u64,
+    flow_control: bool,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-enum WorldResponse {
-    Hello(Result<String, u64>),
+// application error, returned by the service as a value
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HelloError {
+    EmptyName,
+    Rejected(String),
 }
 
-struct WorldClient {
-    channel: ckb_script_ipc_common::channel::Channel,
+#[ckb_script_ipc::service]
+trait World {
+    // note self is not used
+    fn hello(name: String) -> Result<String, HelloError>;
+    fn echo(data: Vec<u8>) -> Vec<u8>;
 }
 
-impl WorldClient {
-    fn new(
-        read: ckb_script_ipc_common::pipe::Pipe,
-        write: ckb_script_ipc_common::pipe::Pipe,
-    ) -> Self {
-        Self {
-            channel: ckb_script_ipc_common::channel::Channel::new(read, write),
-        }
-    }
-}
-
-impl WorldClient {
-    fn hello(&mut self, name: String) -> Result<String, u64> {
-        let request = WorldRequest::Hello { name };
-        let resp: Result<_, ckb_script_ipc_common::error::IpcError> = self
-            .channel
-            .call::<_, WorldResponse>("World.hello", request);
-        match resp {
-            Ok(WorldResponse::Hello(ret)) => ret,
-            Err(e) => {
-                panic!("IPC error: {:?}", e);
-            }
-        }
-    }
-}
-// ---------------------------------
-// end of auto generated code
-// ---------------------------------
-
 // the following code is written by users
 struct WorldServer;
 
 impl World for WorldServer {
-    fn hello(&self, name: String) -> Result<String, u64> {
-        if name == "error" {
-            Err(1)
+    fn hello(&self, name: String) -> Result<String, HelloError> {
+        if name.is_empty() {
+            Err(HelloError::EmptyName)
+        } else if name == "error" {
+            Err(HelloError::Rejected(name))
         } else {
             Ok(format!("hello, {}", name))
         }
     }
+    fn echo(&self, data: Vec<u8>) -> Vec<u8> {
+        data
+    }
 }
 
-pub fn server_entry() -> Result<(), Error> {
+fn server_entry(config: DemoConfig) -> i8 {
     info!("server started");
-    let fds = inherited_fds();
-    assert_eq!(fds.len(), 2);
-    let channel = Channel::new(fds[0].into(), fds[1].into());
-    channel
-        .execute(&mut WorldServer.server())
-        .map_err(|_| Error::ServerError)?;
+    run_server_with(
+        |reader, writer| {
+            let channel = Channel::with_capacity(config.read_capacity as usize, reader, writer);
+            if config.flow_control {
+                channel
+            } else {
+                channel.without_flow_control()
+            }
+        },
+        WorldServer.server(),
+    )
+}
+
+// Sends all the echo requests at once, in as few chunks as possible, while
+// the server responds to the first ones. Without flow control, the client and
+// the server block writing to each other.
+fn echo_pipelined(client: &mut WorldClient<&mut Pipe, &mut Pipe>) -> Result<(), Error> {
+    let requests = (0..ECHO_COUNT).map(|i| WorldRequest::Echo {
+        data: vec![i; ECHO_SIZE],
+    });
+    let responses = client.call_batch(requests).map_err(|_| Error::IpcError)?;
+    for (i, response) in responses.into_iter().enumerate() {
+        match response {
+            Ok(WorldResponse::Echo(data)) if data == vec![i as u8; ECHO_SIZE] => {}
+            _ => return Err(Error::EchoFailed),
+        }
+    }
+    info!("{} echo requests pipelined", ECHO_COUNT);
     Ok(())
 }
 
@@ -117,28 +120,54 @@ pub fn client_entry() -> Result<(), Error> {
     info!("client started");
 
     // server can be spawned by any process which wants to start it.
-    let (read_pipe, write_pipe) = spawn_server(
-        0,
-        Source::CellDep,
-        &[CString::new("demo").unwrap().as_ref()],
-    )
-    .map_err(|_| Error::CkbSysError)?;
-
-    let mut client = WorldClient::new(read_pipe.into(), write_pipe.into());
-    let ret = client.hello("world".into()).unwrap();
+    let args = script_args();
+    let scenario = args.get(1).copied();
+    let config = DemoConfig {
+        read_capacity: read_capacity(&args) as u64,
+        flow_control: scenario != Some(PIPELINED_WITHOUT_FLOW_CONTROL),
+    };
+    let mut server = SpawnBuilder::new(0, Source::CellDep)
+        .config(&config)
+        .and_then(|builder| builder.spawn())
+        .map_err(|_| Error::CkbSysError)?;
+    info!("server spawned, process id: {}", server.process_id());
+
+    let (read_pipe, write_pipe) = server.pipes_mut().map_err(|_| Error::CkbSysError)?;
+    let mut channel = Channel::with_capacity(config.read_capacity as usize, read_pipe, write_pipe);
+    if !config.flow_control {
+        channel = channel.without_flow_control();
+    }
+    let mut client = WorldClient::from(channel);
+    let ret = client
+        .hello("world".into())
+        .map_err(|_| Error::IpcError)?
+        .map_err(|_| Error::HelloFailed)?;
     info!("IPC response: {:?}", ret);
+    if let Some(PIPELINED | PIPELINED_WITHOUT_FLOW_CONTROL) = scenario {
+        echo_pipelined(&mut client)?;
+    }
+    drop(client);
+
+    // closing pipes ends the server loop, which should exit normally
+    server.join().map_err(|_| Error::ServerError)?;
     Ok(())
 }
 
-pub fn entry() -> Result<(), Error> {
+pub fn entry() -> i8 {
     // enable logging by default
     drop(logger::init());
 
-    let argv = argv();
-    if !argv.is_empty() {
-        server_entry()?;
+    // the same script is spawned as the server, with its configuration in
+    // argv: failing to decode it must not start a client spawning it again
+    if !argv().is_empty() {
+        match server_config::<DemoConfig>() {
+            Ok(config) => server_entry(config),
+            Err(_) => Error::ServerError as i8,
+        }
     } else {
-        client_entry()?;
+        match client_entry() {
+            Ok(_) => 0,
+            Err(e) => e as i8,
+        }
     }
-    Ok(())
 }
@@ -3,4 +3,7 @@ pub enum Error {
     Unknown = 1,
     CkbSysError,
     ServerError,
+    IpcError,
+    HelloFailed,
+    EchoFailed,
 }
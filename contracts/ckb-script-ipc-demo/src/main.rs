@@ -15,8 +15,5 @@ ckb_std::entry!(program_entry);
 default_alloc!();
 
 pub fn program_entry() -> i8 {
-    match entry::entry() {
-        Ok(_) => 0,
-        Err(e) => e as i8,
-    }
+    entry::entry()
 }
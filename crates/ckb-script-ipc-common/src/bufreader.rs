@@ -1,7 +1,4 @@
-use crate::{
-    error::IpcError,
-    io::{BufRead, Read},
-};
+use crate::io::{BufRead, Read};
 use alloc::vec;
 use alloc::vec::Vec;
 use core::{cmp, fmt};
@@ -62,17 +59,20 @@ impl<R: ?Sized> BufReader<R> {
     }
 }
 
+// errors of the inner reader are returned unchanged
 impl<R: ?Sized + Read> Read for BufReader<R> {
-    type Error = IpcError;
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IpcError> {
+    type Error = R::Error;
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, R::Error> {
         if self.pos >= self.filled && buf.len() >= self.capacity() {
             self.discard_buffer();
-            return self.inner.read(buf).map_err(|_| IpcError::BufReaderError);
+            return self.inner.read(buf);
         }
 
         let nread = {
-            let mut rem = self.fill_buf()?;
-            rem.read(buf)?
+            let rem = self.fill_buf()?;
+            let n = cmp::min(rem.len(), buf.len());
+            buf[..n].copy_from_slice(&rem[..n]);
+            n
         };
         self.consume(nread);
         Ok(nread)
@@ -80,14 +80,11 @@ impl<R: ?Sized + Read> Read for BufReader<R> {
 }
 
 impl<R: ?Sized + Read> BufRead for BufReader<R> {
-    type Error = IpcError;
-    fn fill_buf(&mut self) -> Result<&[u8], IpcError> {
+    type Error = R::Error;
+    fn fill_buf(&mut self) -> Result<&[u8], R::Error> {
         if self.pos >= self.filled {
             assert_eq!(self.pos, self.filled);
-            self.filled = self
-                .inner
-                .read(&mut self.buf)
-                .map_err(|_| IpcError::BufReaderError)?;
+            self.filled = self.inner.read(&mut self.buf)?;
             self.pos = 0;
         }
         Ok(&self.buf[self.pos..self.filled])
@@ -0,0 +1,201 @@
+use crate::error::IpcError;
+use crate::io::Write;
+use alloc::vec::Vec;
+use core::mem::ManuallyDrop;
+use core::{fmt, ptr};
+
+const DEFAULT_BUF_SIZE: usize = 1024;
+
+/// A simple implementation of BufWriter, for writers of `io::Write`.
+///
+/// It's standalone: `Channel` doesn't use it, as every frame is assembled
+/// before being written with a single `write` already.
+///
+/// Errors of the inner writer are returned unchanged. Like the one in
+/// standard library, buffered data is written out on drop, where errors are
+/// ignored: call `flush`, or `into_inner`, to handle them.
+pub struct BufWriter<W: ?Sized + Write> {
+    buf: Vec<u8>,
+    inner: W,
+}
+
+impl<W: Write> BufWriter<W> {
+    pub fn new(inner: W) -> BufWriter<W> {
+        BufWriter::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: W) -> BufWriter<W> {
+        BufWriter {
+            buf: Vec::with_capacity(capacity),
+            inner,
+        }
+    }
+
+    /// Flushes the buffer and unwraps the inner writer.
+    pub fn into_inner(mut self) -> Result<W, W::Error> {
+        self.flush_buf()?;
+        let this = ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped, so both fields are moved out once.
+        // The buffer is dropped right away, as it's empty.
+        unsafe {
+            drop(ptr::read(&this.buf));
+            Ok(ptr::read(&this.inner))
+        }
+    }
+}
+
+impl<W: ?Sized + Write> BufWriter<W> {
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+}
+
+impl<W: ?Sized + Write> BufWriter<W> {
+    // Data written is dropped from the buffer even if a later write fails, so
+    // that it's never written twice.
+    fn flush_buf(&mut self) -> Result<(), W::Error> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.buf.len() {
+                break Ok(());
+            }
+            match self.inner.write(&self.buf[written..]) {
+                Ok(0) => break Err(IpcError::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) => break Err(e),
+            }
+        };
+        self.buf.drain(..written);
+        result
+    }
+}
+
+impl<W: ?Sized + Write> Write for BufWriter<W> {
+    type Error = W::Error;
+    fn write(&mut self, buf: &[u8]) -> Result<usize, W::Error> {
+        if self.buf.len() + buf.len() > self.capacity() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.capacity() {
+            self.inner.write(buf)
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), W::Error> {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: ?Sized + Write> Drop for BufWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush_buf();
+    }
+}
+
+impl<W> fmt::Debug for BufWriter<W>
+where
+    W: ?Sized + Write + fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("BufWriter")
+            .field("writer", &&self.inner)
+            .field(
+                "buffer",
+                &format_args!("{}/{}", self.buf.len(), self.capacity()),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes up to 2 bytes at a time, fails once after `fail_after` bytes.
+    struct Flaky {
+        out: Vec<u8>,
+        fail_after: usize,
+    }
+
+    impl Write for Flaky {
+        type Error = IpcError;
+        fn write(&mut self, buf: &[u8]) -> Result<usize, IpcError> {
+            if self.out.len() >= self.fail_after {
+                self.fail_after = usize::MAX;
+                return Err(IpcError::EndOfStream);
+            }
+            let n = buf.len().min(2);
+            self.out.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> Result<(), IpcError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_buffered_until_flush() {
+        let mut writer = BufWriter::with_capacity(8, Vec::new());
+        writer.write_all(&[1, 2, 3]).unwrap();
+        writer.write_all(&[4, 5]).unwrap();
+        assert!(writer.get_ref().is_empty());
+        assert_eq!(writer.buffer(), &[1, 2, 3, 4, 5]);
+        // doesn't fit: buffered data is written out first
+        writer.write_all(&[6, 7, 8, 9]).unwrap();
+        assert_eq!(writer.get_ref(), &[1, 2, 3, 4, 5]);
+        writer.flush().unwrap();
+        assert_eq!(writer.get_ref(), &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(writer.buffer().is_empty());
+    }
+
+    #[test]
+    fn test_large_write_bypasses_buffer() {
+        let mut writer = BufWriter::with_capacity(4, Vec::new());
+        writer.write_all(&[1]).unwrap();
+        writer.write_all(&[2, 3, 4, 5, 6, 7]).unwrap();
+        assert_eq!(writer.get_ref(), &[1, 2, 3, 4, 5, 6, 7]);
+        assert!(writer.buffer().is_empty());
+        writer.write_all(&[8]).unwrap();
+        assert_eq!(writer.into_inner().unwrap(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let mut out = Vec::new();
+        let mut writer = BufWriter::with_capacity(8, &mut out);
+        writer.write_all(&[1, 2, 3]).unwrap();
+        drop(writer);
+        assert_eq!(out, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_inner_error_unchanged() {
+        let mut inner = Flaky {
+            out: Vec::new(),
+            fail_after: 3,
+        };
+        let mut writer = BufWriter::with_capacity(8, &mut inner);
+        writer.write_all(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert!(matches!(writer.flush(), Err(IpcError::EndOfStream)));
+        // only the data left is written again, here on drop
+        assert_eq!(writer.buffer(), &[5, 6]);
+        drop(writer);
+        assert_eq!(inner.out, &[1, 2, 3, 4, 5, 6]);
+    }
+}
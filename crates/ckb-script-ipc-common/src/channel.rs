@@ -1,64 +1,228 @@
-use crate::error::ProtocolErrorCode;
-use crate::io::Write;
-use crate::ipc::Serve;
-use crate::packet::{Packet, RequestPacket, ResponsePacket};
+use crate::bufreader::BufReader;
+use crate::duplex::{Duplex, Then};
+use crate::error::{ErrorDetail, ErrorStage, ProtocolErrorCode};
+use crate::io::{Read, Write};
+use crate::ipc::{decode, encode, Message, Serve};
+use crate::packet::{Frame, Packet, RequestPacket, ResponsePacket, DEFAULT_MAX_PAYLOAD_SIZE};
 use crate::{error::IpcError, pipe::Pipe};
-use alloc::vec;
-use serde::{Deserialize, Serialize};
-use serde_molecule::{from_slice, to_vec};
+use alloc::{boxed::Box, format, vec, vec::Vec};
 
-pub struct Channel {
-    reader: Pipe,
-    writer: Pipe,
+/// A channel carrying requests and responses over a reader and a writer.
+/// They are pipes between two scripts by default, but any transport
+/// implementing `io::Read` and `io::Write` can be used, e.g. in-memory
+/// buffers in unit tests.
+///
+/// The reader is buffered, so a packet header and a small payload can be
+/// received with a single `read` syscall.
+///
+/// Data is sent in chunks, taking turns with the other end, so that neither
+/// end blocks the other when both send at once, see the `duplex` module. Both
+/// ends must use the same framing.
+pub struct Channel<R = Pipe, W = Pipe> {
+    duplex: Duplex<R, W>,
+    max_payload_size: usize,
+    // id of the next request sent, 0 is reserved for notifications and
+    // responses not answering a request
+    next_request_id: u64,
+    // serving a loopback transport, where the client can't reply while a
+    // request is served, see `loopback`
+    pub(crate) loopback: bool,
 }
 
-impl Channel {
-    pub fn new(reader: Pipe, writer: Pipe) -> Self {
-        Self { reader, writer }
+impl<R: Read, W> Channel<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            duplex: Duplex::new(BufReader::new(reader), writer),
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            next_request_id: 1,
+            loopback: false,
+        }
+    }
+
+    /// Creates a channel whose reader is buffered with `capacity` bytes.
+    pub fn with_capacity(capacity: usize, reader: R, writer: W) -> Self {
+        Self {
+            duplex: Duplex::new(BufReader::with_capacity(capacity, reader), writer),
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            next_request_id: 1,
+            loopback: false,
+        }
+    }
+
+    /// Sets the maximum payload size of received packets, larger ones are
+    /// rejected with `IpcError::PayloadTooLarge` before being read.
+    /// Defaults to `DEFAULT_MAX_PAYLOAD_SIZE`.
+    ///
+    /// Chunks of data larger than it are rejected the same way, so the chunk
+    /// size of the other end must not exceed it, see `with_chunk_size`.
+    pub fn with_max_payload_size(mut self, max_payload_size: usize) -> Self {
+        self.max_payload_size = max_payload_size;
+        self.duplex.set_max_chunk_size(max_payload_size);
+        self
+    }
+
+    /// Sets the maximum size of the chunks sent. Defaults to
+    /// `duplex::DEFAULT_CHUNK_SIZE`.
+    ///
+    /// It also bounds the data buffered while waiting for the turn, see
+    /// `with_chunk_window`, so both ends should use the same chunk size.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.duplex.set_chunk_size(chunk_size);
+        self
+    }
+
+    /// Sets the maximum number of chunks sent in a row before passing the
+    /// turn, which the other end might have to buffer. Defaults to
+    /// `duplex::DEFAULT_CHUNK_WINDOW`.
+    ///
+    /// Data received while waiting for the turn is buffered up to a window of
+    /// chunks of the chunk size each time the turn is passed, more fails with
+    /// `IpcError::PayloadTooLarge`, so the window of the other end must not
+    /// exceed it.
+    pub fn with_chunk_window(mut self, chunk_window: usize) -> Self {
+        self.duplex.set_chunk_window(chunk_window);
+        self
+    }
+
+    /// Sends and receives packets as is, without chunks nor turns, to talk to
+    /// other ends which don't use chunks. Over pipes between scripts, where a
+    /// write waits for the other end to read it, both ends can block each
+    /// other forever, e.g. with pipelined requests, see `duplex`.
+    pub fn without_flow_control(mut self) -> Self {
+        self.duplex.disable_flow_control();
+        self
+    }
+
+    pub(crate) fn for_loopback(mut self) -> Self {
+        self.loopback = true;
+        self
+    }
+
+    /// Unwraps the reader and the writer. Data buffered in the reader is lost.
+    pub fn into_inner(self) -> (R, W) {
+        self.duplex.into_inner()
     }
 }
 
-impl Channel {
+impl<R, W> Channel<R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
     /// Execute a server loop
     /// 1. receive request
     /// 2. call serve method
     /// 3. send response
     /// 4. continue
+    ///
+    /// A request failing to be decoded or served is answered with an error
+    /// code, and the loop continues. The loop only ends on transport failures,
+    /// or returns `Ok(())` when the client closes its pipe at a packet boundary.
     pub fn execute<Req, Resp, S>(mut self, serve: &mut S) -> Result<(), IpcError>
     where
-        Req: Serialize + for<'de> Deserialize<'de>,
-        Resp: Serialize + for<'de> Deserialize<'de>,
+        Req: Message,
+        Resp: Message,
         S: Serve<Req = Req, Resp = Resp>,
     {
-        loop {
-            let result = self
-                .receive_request()
-                .and_then(|req| serve.serve(req).map_err(Into::into))
-                .and_then(|resp| self.send_response(resp));
-
-            match result {
-                Ok(_) => continue,
-                Err(e) => {
-                    #[cfg(feature = "enable-logging")]
-                    log::error!("Error in execute loop: {:?}", e);
-                    // notify client
-                    self.send_error_code(e.clone().into()).unwrap();
-                    return Err(e);
-                }
+        while self.serve_next(serve)? {}
+        Ok(())
+    }
+    /// Serves a single request, see `execute`. Returns `Ok(false)` when the
+    /// client closes its pipe at a packet boundary.
+    pub fn serve_next<Req, Resp, S>(&mut self, serve: &mut S) -> Result<bool, IpcError>
+    where
+        Req: Message,
+        Resp: Message,
+        S: Serve<Req = Req, Resp = Resp>,
+    {
+        let packet = loop {
+            let e = match self.read_frame() {
+                Ok(Frame::Request(packet)) => break packet,
+                // left over by calls which have ended, see `next_frame`, or
+                // chunks of an upload left unread by the handler
+                Ok(Frame::StreamCredit(_) | Frame::StreamItem(_)) => continue,
+                Ok(frame) => IpcError::UnexpectedPacket(frame.kind()),
+                // the client has closed its pipe after its last request
+                Err(IpcError::EndOfStream) => return Ok(false),
+                Err(e) => e,
+            };
+            #[cfg(feature = "enable-logging")]
+            log::error!("Error in execute loop: {:?}", e);
+            // framing is broken, notify client which might have gone already
+            let _ = self.write_packet(
+                &error_response(0, 0, e.clone(), ErrorStage::ReadRequest, None),
+                Then::Read,
+            );
+            return Err(e);
+        };
+        self.respond(packet, serve)?;
+        Ok(true)
+    }
+    // serves a request, and sends its response unless it's a notification
+    fn respond<S: Serve>(&mut self, packet: RequestPacket, serve: &mut S) -> Result<(), IpcError> {
+        #[cfg(feature = "enable-logging")]
+        log::info!("receive request: {:?}", packet);
+        let request_id = packet.request_id();
+
+        if packet.is_notification() {
+            // nobody is waiting for a response, errors can only be logged
+            let _result = S::Req::decode(packet.method_id(), packet.payload())
+                .and_then(|req| serve.serve_with_peer(req, &mut Peer::new(self, request_id)));
+            #[cfg(feature = "enable-logging")]
+            if let Err(e) = _result {
+                log::error!("Error in serving notification: {:?}", e);
             }
+            return Ok(());
         }
+
+        let mut method = None;
+        let result = S::Req::decode(packet.method_id(), packet.payload())
+            .map_err(|e| (ErrorStage::DecodeRequest, e))
+            .and_then(|req| {
+                method = serve.method(&req);
+                serve
+                    .serve_with_peer(req, &mut Peer::new(self, request_id))
+                    .map_err(|e| (ErrorStage::Serve, e))
+            })
+            .and_then(|resp| resp.encode().map_err(|e| (ErrorStage::EncodeResponse, e)));
+        let packet = match result {
+            Ok(payload) => ResponsePacket::new(packet.request_id(), packet.method_id(), 0, payload),
+            Err((stage, e)) => {
+                #[cfg(feature = "enable-logging")]
+                log::error!("Error in serving request: {:?}", e);
+                error_response(packet.request_id(), packet.method_id(), e, stage, method)
+            }
+        };
+        #[cfg(feature = "enable-logging")]
+        log::info!("send response: {:?}", packet);
+        self.write_packet(&packet, Then::Read)
     }
     // used for client
-    pub fn call<Req, Resp>(
+    pub fn call<Req, Resp>(&mut self, method_name: &'static str, req: Req) -> Result<Resp, IpcError>
+    where
+        Req: Message,
+        Resp: Message,
+    {
+        self.call_with_callbacks(method_name, req, &mut NoCallbacks)
+    }
+    /// Same as `call`, but requests sent by the server while serving this
+    /// one, i.e. callbacks, are served by `callbacks`. See `Peer` for the
+    /// server side.
+    pub fn call_with_callbacks<Req, Resp, S>(
         &mut self,
         _method_name: &'static str,
         req: Req,
+        callbacks: &mut S,
     ) -> Result<Resp, IpcError>
     where
-        Req: Serialize + for<'de> Deserialize<'de>,
-        Resp: Serialize + for<'de> Deserialize<'de>,
+        Req: Message,
+        Resp: Message,
+        S: Serve,
     {
-        let result = self.send_request(req).and_then(|_| self.receive_response());
+        let method_id = req.method_id();
+        let result = self.send_request(req).and_then(|request_id| {
+            self.receive_response_with_callbacks(request_id, method_id, callbacks)
+        });
         match result {
             Ok(resp) => Ok(resp),
             Err(e) => {
@@ -68,56 +232,752 @@ impl Channel {
             }
         }
     }
-    pub fn send_request<Req: Serialize>(&mut self, req: Req) -> Result<(), IpcError> {
-        let serialized_req = to_vec(&req, false).map_err(|_| IpcError::SerializeError)?;
-        let packet = RequestPacket::new(serialized_req);
+    /// Sends a request, returns its request id to receive the response.
+    ///
+    /// Requests can be pipelined: send several of them, then receive their
+    /// responses with `receive_response`, in the same order.
+    pub fn send_request<Req: Message>(&mut self, req: Req) -> Result<u64, IpcError> {
+        let packet = self.request_packet(&req)?;
         #[cfg(feature = "enable-logging")]
         log::info!("send request: {:?}", packet);
 
-        let bytes = packet.serialize();
-        self.writer.write(&bytes)?;
-        Ok(())
+        self.write_packet(&packet, Then::Read)?;
+        Ok(packet.request_id())
     }
-    pub fn send_response<Resp: Serialize>(&mut self, resp: Resp) -> Result<(), IpcError> {
-        let serialized_resp = to_vec(&resp, false).map_err(|_| IpcError::SerializeError)?;
-        let packet = ResponsePacket::new(0, serialized_resp);
+    /// Sends a notification, a request which the server serves without
+    /// responding. The outcome, including any error, is unknown to the client.
+    pub fn send_notification<Req: Message>(&mut self, req: Req) -> Result<(), IpcError> {
+        let packet = RequestPacket::new(0, req.method_id(), req.encode()?);
+        #[cfg(feature = "enable-logging")]
+        log::info!("send notification: {:?}", packet);
+
+        self.write_packet(&packet, Then::Write)
+    }
+    /// Assembles all the requests once, sent in as few chunks as the chunk size
+    /// and window allow, then receives their responses, which the server sends
+    /// in order. Errors of individual
+    /// requests, i.e. error responses and payloads failing to be decoded, are
+    /// returned in place of their responses. Other errors, e.g. of transport,
+    /// leave the channel out of sync and fail the whole batch.
+    pub fn call_batch<Req, Resp, I>(
+        &mut self,
+        reqs: I,
+    ) -> Result<Vec<Result<Resp, IpcError>>, IpcError>
+    where
+        Req: Message,
+        Resp: Message,
+        I: IntoIterator<Item = Req>,
+    {
+        let mut pending = Vec::new();
+        let mut bytes = Vec::new();
+        for req in reqs {
+            let packet = self.request_packet(&req)?;
+            #[cfg(feature = "enable-logging")]
+            log::info!("send request: {:?}", packet);
+            pending.push((packet.request_id(), packet.method_id()));
+            bytes.extend_from_slice(&packet.serialize());
+        }
+        if !bytes.is_empty() {
+            self.write_bytes(&bytes, Then::Read)?;
+        }
+        pending
+            .into_iter()
+            .map(
+                |(request_id, method_id)| match self.receive_response(request_id, method_id) {
+                    Ok(resp) => Ok(Ok(resp)),
+                    Err(e @ (IpcError::ProtocolError(..) | IpcError::DeserializeError)) => {
+                        Ok(Err(e))
+                    }
+                    Err(e) => Err(e),
+                },
+            )
+            .collect()
+    }
+    pub(crate) fn request_packet<Req: Message>(
+        &mut self,
+        req: &Req,
+    ) -> Result<RequestPacket, IpcError> {
+        let payload = req.encode()?;
+        let request_id = self.next_request_id;
+        // skip 0 on wrapping
+        self.next_request_id = self.next_request_id.checked_add(1).unwrap_or(1);
+        Ok(RequestPacket::new(request_id, req.method_id(), payload))
+    }
+    /// Sends the response to request `request_id`.
+    pub fn send_response<Resp: Message>(
+        &mut self,
+        request_id: u64,
+        resp: Resp,
+    ) -> Result<(), IpcError> {
+        let packet = ResponsePacket::new(request_id, resp.method_id(), 0, resp.encode()?);
         #[cfg(feature = "enable-logging")]
         log::info!("send response: {:?}", packet);
 
-        let bytes = packet.serialize();
-        self.writer.write(&bytes)?;
-        Ok(())
+        self.write_packet(&packet, Then::Read)
     }
-    pub fn send_error_code(&mut self, error_code: ProtocolErrorCode) -> Result<(), IpcError> {
-        let packet = ResponsePacket::new(error_code as u64, vec![]);
+    /// Sends an error code, without details, to request `request_id` of
+    /// method `method_id`.
+    pub fn send_error_code(
+        &mut self,
+        request_id: u64,
+        method_id: u64,
+        error_code: ProtocolErrorCode,
+    ) -> Result<(), IpcError> {
+        let error_code = error_code.code();
+        let packet = ResponsePacket::new(request_id, method_id, error_code, vec![]);
         #[cfg(feature = "enable-logging")]
-        log::info!("send error code: {:?}", error_code as u64);
-        let bytes = packet.serialize();
-        self.writer.write(&bytes)?;
-        Ok(())
+        log::info!("send error code: {:?}", error_code);
+        self.write_packet(&packet, Then::Read)
     }
-    pub fn receive_request<Req: for<'de> Deserialize<'de>>(&mut self) -> Result<Req, IpcError> {
-        let packet = RequestPacket::read_from(&mut self.reader)?;
+    // The whole frame is assembled first, so it's emitted with a single
+    // `write` syscall, unless it spans several chunks. As a write might still
+    // be short, e.g. on a pipe, it's written with `write_all`. `then` tells
+    // whether a reply is read next, see `duplex::Then`.
+    pub(crate) fn write_packet<P: Packet>(
+        &mut self,
+        packet: &P,
+        then: Then,
+    ) -> Result<(), IpcError> {
+        self.write_bytes(&packet.serialize(), then)
+    }
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8], then: Then) -> Result<(), IpcError> {
+        self.duplex.write_all(bytes, then)
+    }
+    /// Receives a request, returns it with its request id. The method is
+    /// looked up by the method id of the packet, before deserializing the
+    /// payload.
+    pub fn receive_request<Req: Message>(&mut self) -> Result<(u64, Req), IpcError> {
+        let packet = RequestPacket::read_from_with_limit(&mut self.duplex, self.max_payload_size)?;
         #[cfg(feature = "enable-logging")]
         log::info!("receive request: {:?}", packet);
-        let req = from_slice(packet.payload(), false).map_err(|_| IpcError::DeserializeError)?;
-        Ok(req)
+        let req = Req::decode(packet.method_id(), packet.payload())?;
+        Ok((packet.request_id(), req))
+    }
+    /// Receives the response to request `request_id` of method `method_id`.
+    /// An error response is returned as `IpcError::ProtocolError`, with the
+    /// details sent by the server if they can be decoded.
+    ///
+    /// A response to another request, e.g. from a server replying out of
+    /// order, is returned as `IpcError::ResponseMismatch`. The channel is out
+    /// of sync then, and shouldn't be used anymore.
+    ///
+    /// Requests from the other end, which can't be served here, are answered
+    /// with `UnexpectedRequest` errors.
+    pub fn receive_response<Resp: Message>(
+        &mut self,
+        request_id: u64,
+        method_id: u64,
+    ) -> Result<Resp, IpcError> {
+        self.receive_response_with_callbacks(request_id, method_id, &mut NoCallbacks)
     }
-    pub fn receive_response<Resp: for<'de> Deserialize<'de>>(&mut self) -> Result<Resp, IpcError> {
-        let packet = ResponsePacket::read_from(&mut self.reader)?;
+    /// Same as `receive_response`, but requests from the other end are served
+    /// by `callbacks` while waiting.
+    pub fn receive_response_with_callbacks<Resp: Message, S: Serve>(
+        &mut self,
+        request_id: u64,
+        method_id: u64,
+        callbacks: &mut S,
+    ) -> Result<Resp, IpcError> {
+        match self.next_frame_with_callbacks(0, callbacks)? {
+            Frame::Response(packet) => check_response(packet, request_id, method_id),
+            frame => Err(IpcError::UnexpectedPacket(frame.kind())),
+        }
+    }
+    pub(crate) fn read_frame(&mut self) -> Result<Frame, IpcError> {
+        Frame::read_from_with_limit(&mut self.duplex, self.max_payload_size)
+    }
+    /// Reads the next frame other than a request, answering requests from the
+    /// other end with `UnexpectedRequest` errors meanwhile.
+    ///
+    /// Credits are skipped, except for the streaming response to request
+    /// `stream_id` of the other end, if not 0: they can arrive after the end
+    /// of the stream they are for, as the client grants credit ahead.
+    pub(crate) fn next_frame(&mut self, stream_id: u64) -> Result<Frame, IpcError> {
+        self.next_frame_with_callbacks(stream_id, &mut NoCallbacks)
+    }
+    /// Same as `next_frame`, but requests are served by `callbacks`.
+    fn next_frame_with_callbacks<S: Serve>(
+        &mut self,
+        stream_id: u64,
+        callbacks: &mut S,
+    ) -> Result<Frame, IpcError> {
+        loop {
+            match self.read_frame()? {
+                Frame::Request(packet) => self.respond(packet, callbacks)?,
+                Frame::StreamCredit(packet)
+                    if packet.request_id() != stream_id || stream_id == 0 => {}
+                frame => return Ok(frame),
+            }
+        }
+    }
+    /// Skips frames up to the response to the call in progress.
+    fn skip_to_response(&mut self) -> Result<ResponsePacket, IpcError> {
+        loop {
+            if let Frame::Response(packet) = self.next_frame(0)? {
+                return Ok(packet);
+            }
+        }
+    }
+    /// Ends a call abandoned by the client, e.g. a stream or an upload
+    /// dropped before the end: `bytes`, if any, tell the server to wrap up,
+    /// then the frames up to the response are skipped, to keep the channel in
+    /// sync for later calls. Meant for `Drop`, errors are ignored, as a broken
+    /// channel fails the next call anyway.
+    pub(crate) fn abandon_call(&mut self, bytes: &[u8]) {
+        if !bytes.is_empty() && self.write_bytes(bytes, Then::Read).is_err() {
+            return;
+        }
+        let _ = self.skip_to_response();
+    }
+}
 
+/// Checks a response against its request, and decodes it.
+pub(crate) fn check_response<Resp: Message>(
+    packet: ResponsePacket,
+    request_id: u64,
+    method_id: u64,
+) -> Result<Resp, IpcError> {
+    #[cfg(feature = "enable-logging")]
+    log::info!("Received response: {:?}", packet);
+
+    // error responses not answering any request are accepted
+    let unsolicited_error = packet.request_id() == 0 && packet.error_code() != 0;
+    if (packet.request_id(), packet.method_id()) != (request_id, method_id) && !unsolicited_error {
         #[cfg(feature = "enable-logging")]
-        log::info!("Received response: {:?}", packet);
+        log::error!(
+            "Response mismatch, expected request_id: {}, method_id: {}",
+            request_id,
+            method_id
+        );
+        return Err(IpcError::ResponseMismatch {
+            request_id: packet.request_id(),
+            method_id: packet.method_id(),
+        });
+    }
 
-        let error_code = ProtocolErrorCode::from(packet.error_code());
-        match error_code {
-            ProtocolErrorCode::Ok => {}
-            e => {
-                #[cfg(feature = "enable-logging")]
-                log::error!("Received error code: {:?}", e);
-                return Err(IpcError::ProtocolError(e));
+    let error_code = ProtocolErrorCode::from(packet.error_code());
+    match error_code {
+        ProtocolErrorCode::Ok => {}
+        e => {
+            let detail = if packet.payload().is_empty() {
+                None
+            } else {
+                decode::<ErrorDetail>(packet.payload()).ok().map(Box::new)
+            };
+            #[cfg(feature = "enable-logging")]
+            log::error!("Received error code: {:?}, detail: {:?}", e, detail);
+            return Err(IpcError::ProtocolError(e, detail));
+        }
+    }
+    Resp::decode(method_id, packet.payload())
+}
+
+/// The other end of a channel, given to a handler serving a request from it,
+/// see `Serve::serve_with_peer`. The handler can call back the other end,
+/// which serves the callbacks while waiting for its own response, see
+/// `Channel::call_with_callbacks`.
+///
+/// Requests are matched with their responses by request ids, which each end
+/// assigns to its own requests. A callback can't call back in turn though:
+/// such nested requests are answered with `UnexpectedRequest` errors, as the
+/// handler which could serve them is busy.
+pub struct Peer<'a, R, W> {
+    pub(crate) channel: &'a mut Channel<R, W>,
+    // id of the request being served
+    pub(crate) request_id: u64,
+}
+
+impl<'a, R, W> Peer<'a, R, W> {
+    fn new(channel: &'a mut Channel<R, W>, request_id: u64) -> Self {
+        Self {
+            channel,
+            request_id,
+        }
+    }
+}
+
+impl<'a, R, W> Peer<'a, R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    /// Calls back the client. Fails with `IpcError::CallbackUnsupported` over
+    /// a loopback transport.
+    pub fn call<Req, Resp>(&mut self, method_name: &'static str, req: Req) -> Result<Resp, IpcError>
+    where
+        Req: Message,
+        Resp: Message,
+    {
+        if self.channel.loopback {
+            return Err(IpcError::CallbackUnsupported);
+        }
+        self.channel.call(method_name, req)
+    }
+
+    /// Notifies the client. Fails with `IpcError::CallbackUnsupported` over a
+    /// loopback transport.
+    pub fn send_notification<Req: Message>(&mut self, req: Req) -> Result<(), IpcError> {
+        if self.channel.loopback {
+            return Err(IpcError::CallbackUnsupported);
+        }
+        self.channel.send_notification(req)
+    }
+}
+
+// serves no request, for ends which don't expect any
+struct NoCallbacks;
+
+enum NoRequest {}
+
+impl Message for NoRequest {
+    fn method_id(&self) -> u64 {
+        match *self {}
+    }
+    fn encode(&self) -> Result<Vec<u8>, IpcError> {
+        match *self {}
+    }
+    fn decode(method_id: u64, _payload: &[u8]) -> Result<Self, IpcError> {
+        Err(IpcError::UnexpectedRequest(method_id))
+    }
+}
+
+impl Serve for NoCallbacks {
+    type Req = NoRequest;
+    type Resp = NoRequest;
+    fn serve(&mut self, req: NoRequest) -> Result<NoRequest, IpcError> {
+        match req {}
+    }
+}
+
+/// Builds the response to a failed request, with the error details in payload.
+fn error_response(
+    request_id: u64,
+    method_id: u64,
+    e: IpcError,
+    stage: ErrorStage,
+    method: Option<&str>,
+) -> ResponsePacket {
+    let (code, detail) = match e {
+        IpcError::ProtocolError(code, Some(detail)) => (code, *detail),
+        e => (
+            ProtocolErrorCode::from(e.clone()),
+            ErrorDetail::new(format!("{:?}", e)),
+        ),
+    };
+    let mut detail = detail;
+    if detail.stage() == ErrorStage::Unknown {
+        detail.stage = stage as u8;
+    }
+    if let (true, Some(method)) = (detail.method.is_empty(), method) {
+        detail.method = method.into();
+    }
+    // the error code alone is still meaningful
+    let payload = encode(&detail).unwrap_or_default();
+    ResponsePacket::new(request_id, method_id, code.code(), payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::loopback;
+    use crate::test_utils::{
+        chunks, request, response, spawn, turns, unchunk, AddRequest, AddResponse, Adder,
+        CallingBack, Spawned, ADD,
+    };
+    use ckb_std::error::SysError;
+
+    // a writer accepting at most `max` bytes in every `write`
+    struct ShortWriter {
+        data: Vec<u8>,
+        max: usize,
+        writes: usize,
+    }
+
+    impl ShortWriter {
+        fn new(max: usize) -> Self {
+            Self {
+                data: Vec::new(),
+                max,
+                writes: 0,
+            }
+        }
+    }
+
+    impl Write for ShortWriter {
+        type Error = IpcError;
+        fn write(&mut self, buf: &[u8]) -> Result<usize, IpcError> {
+            let n = core::cmp::min(buf.len(), self.max);
+            self.data.extend_from_slice(&buf[..n]);
+            self.writes += 1;
+            Ok(n)
+        }
+        fn flush(&mut self) -> Result<(), IpcError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_execute_in_memory() {
+        // the server passes the turn back after each request
+        let turns = turns(3);
+        let mut requests = Vec::new();
+        let mut client = Channel::new(&turns[..], &mut requests);
+        client.send_request(AddRequest { a: 1, b: 2 }).unwrap();
+        client
+            .send_request(AddRequest { a: u64::MAX, b: 1 })
+            .unwrap();
+        client
+            .write_packet(&RequestPacket::new(100, 42, vec![]), Then::Read)
+            .unwrap();
+        client.send_request(AddRequest { a: 3, b: 4 }).unwrap();
+
+        let mut responses = Vec::new();
+        Channel::new(&requests[..], &mut responses)
+            .execute(&mut Adder)
+            .unwrap();
+
+        let mut client = Channel::new(&responses[..], Vec::new());
+        let resp: AddResponse = client.receive_response(1, ADD).unwrap();
+        assert_eq!(resp.0, 3);
+        assert!(matches!(
+            client.receive_response::<AddResponse>(2, ADD),
+            Err(IpcError::ProtocolError(ProtocolErrorCode::InvalidData, _))
+        ));
+        assert!(matches!(
+            client.receive_response::<AddResponse>(100, 42),
+            Err(IpcError::ProtocolError(ProtocolErrorCode::UnknownMethod, _))
+        ));
+        let resp: AddResponse = client.receive_response(3, ADD).unwrap();
+        assert_eq!(resp.0, 7);
+        assert!(matches!(
+            client.receive_response::<AddResponse>(4, ADD),
+            Err(IpcError::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn test_short_writes() {
+        let turns = turns(1);
+        let mut writer = ShortWriter::new(1);
+        let mut client = Channel::new(&turns[..], &mut writer);
+        client.send_request(AddRequest { a: 1, b: 2 }).unwrap();
+        client.send_request(AddRequest { a: 3, b: 4 }).unwrap();
+        assert!(writer.writes > 2);
+
+        let mut responses = ShortWriter::new(3);
+        Channel::new(&writer.data[..], &mut responses)
+            .execute(&mut Adder)
+            .unwrap();
+
+        let mut client = Channel::new(&responses.data[..], Vec::new());
+        let resp: AddResponse = client.receive_response(1, ADD).unwrap();
+        assert_eq!(resp.0, 3);
+        let resp: AddResponse = client.receive_response(2, ADD).unwrap();
+        assert_eq!(resp.0, 7);
+    }
+
+    // a reader failing once its data is read
+    struct FailingReader<'a>(&'a [u8]);
+
+    impl Read for FailingReader<'_> {
+        type Error = IpcError;
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, IpcError> {
+            if self.0.is_empty() {
+                return Err(IpcError::CkbSysError(SysError::InvalidFd));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_read_error() {
+        // returned unchanged, within a packet or a chunk too
+        let plain = request(1, 1, 2);
+        let chunked = chunks(&[request(1, 1, 2)]);
+        for (bytes, flow_control) in [(&plain[..3], false), (&chunked[..3], true)] {
+            let mut channel = Channel::new(FailingReader(bytes), Vec::new());
+            if !flow_control {
+                channel = channel.without_flow_control();
+            }
+            assert!(matches!(
+                channel.receive_request::<AddRequest>(),
+                Err(IpcError::CkbSysError(SysError::InvalidFd))
+            ));
+        }
+        let mut channel = Channel::with_capacity(1, FailingReader(&[]), Vec::new());
+        assert!(matches!(
+            channel.receive_request::<AddRequest>(),
+            Err(IpcError::CkbSysError(SysError::InvalidFd))
+        ));
+    }
+
+    #[test]
+    fn test_write_zero() {
+        let mut writer = ShortWriter::new(0);
+        let mut client = Channel::new(&[][..], &mut writer);
+        assert!(matches!(
+            client.send_request(AddRequest { a: 1, b: 2 }),
+            Err(IpcError::WriteZero)
+        ));
+    }
+
+    #[test]
+    fn test_single_write_per_packet() {
+        let turns = turns(1);
+        let mut writer = ShortWriter::new(usize::MAX);
+        let mut client = Channel::new(&turns[..], &mut writer);
+        client.send_request(AddRequest { a: 1, b: 2 }).unwrap();
+        client.send_request(AddRequest { a: 3, b: 4 }).unwrap();
+        assert_eq!(writer.writes, 2);
+    }
+
+    #[test]
+    fn test_max_payload_size() {
+        let mut requests = Vec::new();
+        let mut client = Channel::new(&[][..], &mut requests);
+        client.send_request(AddRequest { a: 1, b: 2 }).unwrap();
+
+        let mut responses = Vec::new();
+        let result = Channel::new(&requests[..], &mut responses)
+            .with_max_payload_size(1)
+            .execute(&mut Adder);
+        assert!(matches!(result, Err(IpcError::PayloadTooLarge(_))));
+
+        let mut client = Channel::new(&responses[..], Vec::new());
+        assert!(matches!(
+            client.receive_response::<AddResponse>(1, ADD),
+            Err(IpcError::ProtocolError(
+                ProtocolErrorCode::PayloadTooLarge,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_error_detail() {
+        let turns = turns(1);
+        let mut requests = Vec::new();
+        let mut client = Channel::new(&turns[..], &mut requests);
+        client
+            .send_request(AddRequest { a: u64::MAX, b: 1 })
+            .unwrap();
+        client
+            .write_packet(&RequestPacket::new(100, 42, vec![]), Then::Read)
+            .unwrap();
+        let mut responses = Vec::new();
+        Channel::new(&requests[..], &mut responses)
+            .execute(&mut Adder)
+            .unwrap();
+
+        let mut client = Channel::new(&responses[..], Vec::new());
+        match client.receive_response::<AddResponse>(1, ADD) {
+            Err(IpcError::ProtocolError(ProtocolErrorCode::InvalidData, Some(detail))) => {
+                assert_eq!(detail.message, "overflow");
+                assert_eq!(detail.method, "Adder.add");
+                assert_eq!(detail.stage(), ErrorStage::Serve);
+                assert_eq!(detail.app_error::<u64>().unwrap(), u64::MAX);
+            }
+            _ => panic!("expect error detail"),
+        }
+        match client.receive_response::<AddResponse>(100, 42) {
+            Err(IpcError::ProtocolError(ProtocolErrorCode::UnknownMethod, Some(detail))) => {
+                assert_eq!(detail.message, "UnknownMethod(42)");
+                assert!(detail.method.is_empty());
+                assert_eq!(detail.stage(), ErrorStage::DecodeRequest);
             }
+            _ => panic!("expect error detail"),
+        }
+    }
+
+    #[test]
+    fn test_response_mismatch() {
+        let turns = turns(1);
+        let mut requests = Vec::new();
+        let mut client = Channel::new(&turns[..], &mut requests);
+        assert_eq!(client.send_request(AddRequest { a: 1, b: 2 }).unwrap(), 1);
+        assert_eq!(client.send_request(AddRequest { a: 3, b: 4 }).unwrap(), 2);
+
+        let mut responses = Vec::new();
+        Channel::new(&requests[..], &mut responses)
+            .execute(&mut Adder)
+            .unwrap();
+
+        let mut client = Channel::new(&responses[..], Vec::new());
+        assert!(matches!(
+            client.receive_response::<AddResponse>(2, ADD),
+            Err(IpcError::ResponseMismatch {
+                request_id: 1,
+                method_id: ADD
+            })
+        ));
+        assert!(matches!(
+            client.receive_response::<AddResponse>(2, 42),
+            Err(IpcError::ResponseMismatch {
+                request_id: 2,
+                method_id: ADD
+            })
+        ));
+    }
+
+    #[test]
+    fn test_call_batch() {
+        let (reader, writer) = loopback(Adder);
+        let mut client = Channel::new(reader, writer);
+        let resps: Vec<Result<AddResponse, IpcError>> = client
+            .call_batch([
+                AddRequest { a: 1, b: 2 },
+                AddRequest { a: u64::MAX, b: 1 },
+                AddRequest { a: 3, b: 4 },
+            ])
+            .unwrap();
+        assert_eq!(resps.len(), 3);
+        assert_eq!(resps[0].as_ref().unwrap().0, 3);
+        assert!(matches!(
+            resps[1],
+            Err(IpcError::ProtocolError(ProtocolErrorCode::InvalidData, _))
+        ));
+        assert_eq!(resps[2].as_ref().unwrap().0, 7);
+
+        let resps: Vec<Result<AddResponse, IpcError>> =
+            client.call_batch(Vec::<AddRequest>::new()).unwrap();
+        assert!(resps.is_empty());
+        // request ids continue after the batch
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 5, b: 6 }).unwrap();
+        assert_eq!(resp.0, 11);
+    }
+
+    #[test]
+    fn test_call_batch_single_write() {
+        let mut writer = ShortWriter::new(usize::MAX);
+        let mut client = Channel::new(&[][..], &mut writer);
+        assert!(matches!(
+            client.call_batch::<_, AddResponse, _>([
+                AddRequest { a: 1, b: 2 },
+                AddRequest { a: 3, b: 4 },
+            ]),
+            Err(IpcError::EndOfStream)
+        ));
+        assert_eq!(writer.writes, 1);
+
+        let mut responses = Vec::new();
+        Channel::new(&writer.data[..], &mut responses)
+            .execute(&mut Adder)
+            .unwrap();
+        let mut client = Channel::new(&responses[..], Vec::new());
+        let resp: AddResponse = client.receive_response(1, ADD).unwrap();
+        assert_eq!(resp.0, 3);
+        let resp: AddResponse = client.receive_response(2, ADD).unwrap();
+        assert_eq!(resp.0, 7);
+
+        // a batch larger than a chunk is split, but not per request
+        let len = [request(1, 1, 2), request(2, 3, 4)].concat().len();
+        let mut writer = ShortWriter::new(usize::MAX);
+        let mut client = Channel::new(&[][..], &mut writer).with_chunk_size(len.div_ceil(2));
+        assert!(matches!(
+            client.call_batch::<_, AddResponse, _>([
+                AddRequest { a: 1, b: 2 },
+                AddRequest { a: 3, b: 4 },
+            ]),
+            Err(IpcError::EndOfStream)
+        ));
+        assert_eq!(writer.writes, 2);
+        assert_eq!(
+            unchunk(&writer.data),
+            [request(1, 1, 2), request(2, 3, 4)].concat()
+        );
+    }
+
+    #[test]
+    fn test_notification() {
+        let mut requests = Vec::new();
+        let mut client = Channel::new(&[][..], &mut requests);
+        client.send_notification(AddRequest { a: 1, b: 2 }).unwrap();
+        client
+            .send_notification(AddRequest { a: u64::MAX, b: 1 })
+            .unwrap();
+        client.send_request(AddRequest { a: 3, b: 4 }).unwrap();
+
+        let mut responses = Vec::new();
+        Channel::new(&requests[..], &mut responses)
+            .execute(&mut Adder)
+            .unwrap();
+
+        // only the request is answered, even the failed notification isn't
+        let mut client = Channel::new(&responses[..], Vec::new());
+        let resp: AddResponse = client.receive_response(1, ADD).unwrap();
+        assert_eq!(resp.0, 7);
+        assert!(matches!(
+            client.receive_response::<AddResponse>(2, ADD),
+            Err(IpcError::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn test_call_with_callbacks() {
+        // a callback from the server, then the response
+        let incoming = chunks(&[request(1, 1, 2), response(1, 10)]);
+
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing);
+        let resp: AddResponse = client
+            .call_with_callbacks("Adder.add", AddRequest { a: 5, b: 5 }, &mut Adder)
+            .unwrap();
+        assert_eq!(resp.0, 10);
+        assert_eq!(
+            unchunk(&outgoing),
+            [request(1, 5, 5), response(1, 3)].concat()
+        );
+
+        // without callbacks, the callback is answered with an error
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing);
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 5, b: 5 }).unwrap();
+        assert_eq!(resp.0, 10);
+        let outgoing = unchunk(&outgoing);
+        let mut reader = &outgoing[request(1, 5, 5).len()..];
+        assert!(matches!(
+            ResponsePacket::read_from(&mut reader).map(|p| (p.request_id(), p.error_code())),
+            Ok((1, code)) if code == ProtocolErrorCode::UnexpectedRequest.code()
+        ));
+    }
+
+    #[test]
+    fn test_serve_with_peer() {
+        // a request, a nested request from the client while the server is
+        // calling it back, and the response to the callback
+        let incoming = chunks(&[request(1, 2, 1), request(2, 0, 0), response(1, 4)]);
+
+        let mut outgoing = Vec::new();
+        Channel::new(&incoming[..], &mut outgoing)
+            .execute(&mut CallingBack)
+            .unwrap();
+
+        let outgoing = unchunk(&outgoing);
+        let mut reader = &outgoing[..];
+        assert!(matches!(
+            Frame::read_from(&mut reader),
+            Ok(Frame::Request(p)) if p.request_id() == 1 && p.payload() == AddRequest { a: 2, b: 2 }.encode().unwrap()
+        ));
+        assert!(matches!(
+            Frame::read_from(&mut reader),
+            Ok(Frame::Response(p)) if p.request_id() == 2
+                && p.error_code() == ProtocolErrorCode::UnexpectedRequest.code()
+        ));
+        assert_eq!(reader, response(1, 5));
+    }
+
+    #[test]
+    fn test_callbacks_over_pipes() {
+        let Spawned {
+            mut client, server, ..
+        } = spawn(CallingBack, true);
+        for a in 0..4 {
+            let resp: AddResponse = client
+                .call_with_callbacks("CallingBack", AddRequest { a, b: 1 }, &mut Adder)
+                .unwrap();
+            assert_eq!(resp.0, a + a + 1);
         }
-        from_slice(packet.payload(), false).map_err(|_| IpcError::DeserializeError)
+        drop(client);
+        server.join().unwrap().unwrap();
     }
 }
@@ -0,0 +1,490 @@
+//!
+//! Framing of the data sent over a channel in chunks, taking turns, so that
+//! both ends can send at any time without blocking each other.
+//!
+//! A write into a pipe blocks until the other end reads it. Two scripts
+//! writing to each other at the same time block forever, e.g. a client
+//! sending pipelined requests while the server sends a large response. So
+//! only the end having the turn sends, in chunks of bounded size, and the
+//! other end always reads:
+//!
+//! - an end which wants to send without having the turn reads the chunks sent
+//!   meanwhile, buffering them, until it gets the turn;
+//! - an end which wants to receive while having the turn passes it, with its
+//!   last chunk if it expects a reply, e.g. a request, otherwise with an empty
+//!   chunk.
+//!
+//! Writes not expecting a reply, e.g. notifications and stream items, keep
+//! the turn, so several of them cost a single round trip. An end sending a
+//! window of chunks in a row passes the turn, so the other end gets to send
+//! too. An end waiting for the turn buffers at most a window of chunks, more
+//! fails with `IpcError::PayloadTooLarge`. The first end to send gets
+//! the turn, which is the client.
+//!
+//! A chunk is its length in VLQ, shifted left by two bits with the flags
+//! `PASS` and `MORE` in the lowest ones, followed by the data.
+//!
+use crate::bufreader::BufReader;
+use crate::error::IpcError;
+use crate::io::{Read, Write};
+use crate::packet::{read_next_vlq, read_payload, DEFAULT_MAX_PAYLOAD_SIZE};
+use crate::vlq::{vlq_encode_into, MAX_VLQ_LEN};
+use alloc::vec::Vec;
+use ckb_std::error::SysError;
+use core::cmp;
+
+/// Default maximum size of the chunks sent.
+pub const DEFAULT_CHUNK_SIZE: usize = 4096;
+
+/// Default maximum number of chunks sent in a row, before passing the turn.
+/// With the default chunk size, an end waiting for the turn buffers at most
+/// 32 KiB each time it passes the turn.
+pub const DEFAULT_CHUNK_WINDOW: usize = 8;
+
+/// Flag of a chunk passing the turn to the other end.
+pub(crate) const PASS: u64 = 1;
+/// Flag of a chunk passing the turn only because the window is exhausted, the
+/// end keeps writing afterwards.
+pub(crate) const MORE: u64 = 2;
+
+/// What an end does after a write, which decides whether it passes the turn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Then {
+    /// Waits for a reply, e.g. after a request.
+    Read,
+    /// Keeps sending, e.g. after a notification or a stream item.
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Turn {
+    // nothing sent or received yet
+    Unknown,
+    Mine,
+    Peer,
+}
+
+/// Both directions of a channel, reading and writing in chunks. Without flow
+/// control, data is read and written as is.
+pub(crate) struct Duplex<R, W> {
+    reader: BufReader<R>,
+    writer: W,
+    flow_control: bool,
+    chunk_size: usize,
+    // chunks received larger than it are rejected
+    max_chunk_size: usize,
+    chunk_window: usize,
+    turn: Turn,
+    // chunks sent since getting the turn
+    sent: usize,
+    // data buffered since passing the turn, while waiting for it
+    received: usize,
+    // data received, but not read yet
+    inbound: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read, W> Duplex<R, W> {
+    pub(crate) fn new(reader: BufReader<R>, writer: W) -> Self {
+        Self {
+            reader,
+            writer,
+            flow_control: true,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_chunk_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            chunk_window: DEFAULT_CHUNK_WINDOW,
+            turn: Turn::Unknown,
+            sent: 0,
+            received: 0,
+            inbound: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub(crate) fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+
+    pub(crate) fn set_max_chunk_size(&mut self, max_chunk_size: usize) {
+        self.max_chunk_size = max_chunk_size;
+    }
+
+    pub(crate) fn set_chunk_window(&mut self, chunk_window: usize) {
+        self.chunk_window = chunk_window.max(1);
+    }
+
+    pub(crate) fn disable_flow_control(&mut self) {
+        self.flow_control = false;
+    }
+
+    pub(crate) fn into_inner(self) -> (R, W) {
+        (self.reader.into_inner(), self.writer)
+    }
+}
+
+impl<R, W> Duplex<R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    /// Writes all the bytes, in as many chunks as needed. The turn is passed
+    /// with the last chunk if the end reads `then`, and nothing received is
+    /// left to read.
+    pub(crate) fn write_all(&mut self, bytes: &[u8], then: Then) -> Result<(), IpcError> {
+        if !self.flow_control {
+            self.writer.write_all(bytes)?;
+            return self.writer.flush();
+        }
+        let mut chunks = bytes.chunks(self.chunk_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            while self.turn == Turn::Peer {
+                // chunks sent meanwhile are kept for later reads, up to a
+                // window of chunks, which the other end sends at most before
+                // passing the turn back
+                match self.receive()? {
+                    Some((len, _)) => self.received += len,
+                    None => return Err(IpcError::EndOfStream),
+                }
+                if self.received > self.chunk_window.saturating_mul(self.chunk_size) {
+                    return Err(IpcError::PayloadTooLarge(self.received as u64));
+                }
+            }
+            let last = chunks.peek().is_none();
+            let flags = if last && then == Then::Read && self.pos == self.inbound.len() {
+                PASS
+            } else if self.sent + 1 < self.chunk_window {
+                0
+            } else if last && then == Then::Read {
+                PASS
+            } else {
+                PASS | MORE
+            };
+            self.send(chunk, flags)?;
+        }
+        Ok(())
+    }
+
+    // Assembled first, like frames, see `Channel::write_packet`.
+    fn send(&mut self, data: &[u8], flags: u64) -> Result<(), IpcError> {
+        self.writer.write_all(&serialize_chunk(data, flags))?;
+        self.writer.flush()?;
+        self.sent += 1;
+        self.turn = if flags & PASS != 0 {
+            self.received = 0;
+            Turn::Peer
+        } else {
+            Turn::Mine
+        };
+        Ok(())
+    }
+
+    // Receives a chunk into the inbound buffer. Returns the length of its data
+    // and its flags, or `None` if the other end is closed.
+    fn receive(&mut self) -> Result<Option<(usize, u64)>, IpcError> {
+        let (data, flags) = match read_chunk(&mut self.reader, self.max_chunk_size)? {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+        if flags & PASS != 0 {
+            self.turn = Turn::Mine;
+            self.sent = 0;
+        } else {
+            self.turn = Turn::Peer;
+        }
+        let len = data.len();
+        if self.pos == self.inbound.len() {
+            self.inbound = data;
+            self.pos = 0;
+        } else {
+            self.inbound.extend(data);
+        }
+        Ok(Some((len, flags)))
+    }
+
+    fn read_chunked(&mut self, buf: &mut [u8]) -> Result<usize, IpcError> {
+        while self.pos == self.inbound.len() {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let passed = self.turn == Turn::Mine;
+            if passed {
+                match self.send(&[], PASS) {
+                    Ok(()) => {}
+                    // nothing more to receive either
+                    Err(IpcError::CkbSysError(SysError::OtherEndClosed)) => return Ok(0),
+                    Err(e) => return Err(e),
+                }
+            }
+            match self.receive()? {
+                None => return Ok(0),
+                // the other end passed the turn back, it waits for data too
+                Some((0, _)) if passed => return Err(IpcError::Deadlock),
+                Some(_) => {}
+            }
+        }
+        let n = cmp::min(buf.len(), self.inbound.len() - self.pos);
+        buf[..n].copy_from_slice(&self.inbound[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R, W> Read for Duplex<R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    type Error = IpcError;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IpcError> {
+        if !self.flow_control {
+            return self.reader.read(buf);
+        }
+        self.read_chunked(buf)
+    }
+}
+
+fn chunk_header(len: usize, flags: u64) -> u64 {
+    (len as u64) << 2 | flags
+}
+
+/// Reads a chunk, returns its data and its flags, or `None` if the reader is
+/// at its end. A chunk larger than `max_chunk_size` is rejected with
+/// `IpcError::PayloadTooLarge` before its data is read.
+pub(crate) fn read_chunk<R: Read<Error = IpcError>>(
+    reader: &mut R,
+    max_chunk_size: usize,
+) -> Result<Option<(Vec<u8>, u64)>, IpcError> {
+    let header = match read_next_vlq(reader) {
+        Ok(header) => header,
+        Err(IpcError::UnexpectedEof) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let data = read_payload(reader, header >> 2, max_chunk_size)?;
+    Ok(Some((data, header & (PASS | MORE))))
+}
+
+/// Serializes `data` as a single chunk.
+pub(crate) fn serialize_chunk(data: &[u8], flags: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(MAX_VLQ_LEN + data.len());
+    vlq_encode_into(chunk_header(data.len(), flags), &mut buf);
+    buf.extend_from_slice(data);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::channel::Channel;
+    use crate::test_utils::{
+        passes, request, response, spawn, AddRequest, AddResponse, Adder, Range, Spawned, ADD,
+    };
+    use alloc::vec;
+
+    #[test]
+    fn test_chunks() {
+        let request = request(1, 1, 2);
+        let chunks: Vec<&[u8]> = request.chunks(8).collect();
+        // the server waits for the whole request, which passes the turn
+        let incoming = serialize_chunk(&response(1, 3), PASS);
+
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing).with_chunk_size(8);
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 1, b: 2 }).unwrap();
+        assert_eq!(resp.0, 3);
+        let expected: Vec<u8> = chunks
+            .iter()
+            .enumerate()
+            .flat_map(|(i, chunk)| {
+                serialize_chunk(chunk, if i + 1 < chunks.len() { 0 } else { PASS })
+            })
+            .collect();
+        assert_eq!(outgoing, expected);
+    }
+
+    #[test]
+    fn test_chunk_window() {
+        let request = request(1, 1, 2);
+        let chunks: Vec<&[u8]> = request.chunks(4).collect();
+        assert!(chunks.len() > 3);
+        // the turn is passed every 2 chunks, the server passes it back until
+        // it has the whole request
+        let mut incoming = vec![serialize_chunk(&[], PASS); (chunks.len() - 1) / 2].concat();
+        incoming.extend(serialize_chunk(&response(1, 3), PASS));
+
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing)
+            .with_chunk_size(4)
+            .with_chunk_window(2);
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 1, b: 2 }).unwrap();
+        assert_eq!(resp.0, 3);
+        let expected: Vec<u8> = chunks
+            .iter()
+            .enumerate()
+            .flat_map(|(i, chunk)| {
+                let flags = if i + 1 == chunks.len() {
+                    PASS
+                } else if i % 2 == 1 {
+                    PASS | MORE
+                } else {
+                    0
+                };
+                serialize_chunk(chunk, flags)
+            })
+            .collect();
+        assert_eq!(outgoing, expected);
+    }
+
+    #[test]
+    fn test_inbound_limit() {
+        // the other end keeps sending while this end waits for the turn
+        let incoming = serialize_chunk(&[0; 50], 0).repeat(3);
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing)
+            .with_chunk_size(50)
+            .with_chunk_window(2);
+        client.send_request(AddRequest { a: 1, b: 2 }).unwrap();
+        assert!(matches!(
+            client.send_request(AddRequest { a: 3, b: 4 }),
+            Err(IpcError::PayloadTooLarge(150))
+        ));
+
+        // every window is bounded, the data buffered during earlier ones
+        // isn't read while this end keeps writing
+        let incoming = [
+            serialize_chunk(&[0; 50], 0),
+            serialize_chunk(&[0; 50], PASS | MORE),
+            serialize_chunk(&[0; 50], 0).repeat(3),
+        ]
+        .concat();
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing)
+            .with_chunk_size(50)
+            .with_chunk_window(2);
+        assert!(matches!(
+            client.write_bytes(&[0; 400], Then::Read),
+            Err(IpcError::PayloadTooLarge(150))
+        ));
+        assert_eq!(passes(&outgoing), 2);
+    }
+
+    #[test]
+    fn test_oversized_chunk() {
+        // rejected before its data is received
+        let mut incoming = Vec::new();
+        vlq_encode_into(chunk_header(1 << 20, PASS), &mut incoming);
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing).with_max_payload_size(1024);
+        assert!(matches!(
+            client.call::<_, AddResponse>("Adder.add", AddRequest { a: 1, b: 2 }),
+            Err(IpcError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_both_ends_waiting() {
+        let incoming = [
+            serialize_chunk(&response(1, 3), PASS),
+            serialize_chunk(&[], PASS),
+        ]
+        .concat();
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing);
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 1, b: 2 }).unwrap();
+        assert_eq!(resp.0, 3);
+        // no request is pending, the server passes the turn back
+        assert!(matches!(
+            client.receive_response::<AddResponse>(2, ADD),
+            Err(IpcError::Deadlock)
+        ));
+        let expected = [
+            serialize_chunk(&request(1, 1, 2), PASS),
+            serialize_chunk(&[], PASS),
+        ]
+        .concat();
+        assert_eq!(outgoing, expected);
+    }
+
+    fn batch() -> Vec<AddRequest> {
+        (0..16).map(|a| AddRequest { a, b: 1 }).collect()
+    }
+
+    #[test]
+    fn test_pipelined_deadlock() {
+        // the server responds to the first request while the client still
+        // sends the next ones, both ends block
+        let Spawned {
+            mut client, server, ..
+        } = spawn(Adder, false);
+        assert!(matches!(
+            client.call_batch::<_, AddResponse, _>(batch()),
+            Err(IpcError::Deadlock)
+        ));
+        drop(client);
+        assert!(matches!(server.join().unwrap(), Err(IpcError::Deadlock)));
+    }
+
+    #[test]
+    fn test_pipelined_with_flow_control() {
+        let Spawned {
+            mut client, server, ..
+        } = spawn(Adder, true);
+        let resps = client.call_batch::<_, AddResponse, _>(batch()).unwrap();
+        let sums: Vec<u64> = resps.into_iter().map(|resp| resp.unwrap().0).collect();
+        assert_eq!(sums, (1..17).collect::<Vec<u64>>());
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 2, b: 3 }).unwrap();
+        assert_eq!(resp.0, 5);
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_notification_round_trips() {
+        let Spawned {
+            client,
+            server,
+            client_bytes,
+            ..
+        } = spawn(Adder, true);
+        // a chunk per notification
+        let mut client = client.with_chunk_size(DEFAULT_CHUNK_SIZE);
+        for a in 0..40 {
+            client.send_notification(AddRequest { a, b: 1 }).unwrap();
+        }
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 2, b: 3 }).unwrap();
+        assert_eq!(resp.0, 5);
+        drop(client);
+        server.join().unwrap().unwrap();
+        // the turn is passed once per window of notifications, then with the
+        // request
+        assert_eq!(
+            passes(&client_bytes.lock().unwrap()),
+            40 / DEFAULT_CHUNK_WINDOW + 1
+        );
+    }
+
+    #[test]
+    fn test_stream_round_trips() {
+        let Spawned {
+            mut client,
+            server,
+            server_bytes,
+            ..
+        } = spawn(Range, true);
+        let mut stream = client
+            .call_stream_with_window::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 40 }, 64)
+            .unwrap();
+        let items: Result<Vec<u64>, IpcError> = (&mut stream).collect();
+        assert_eq!(items.unwrap(), (0..40).collect::<Vec<u64>>());
+        assert_eq!(stream.finish().unwrap().0, 1);
+        drop(client);
+        server.join().unwrap().unwrap();
+        // the turn is passed once per window of items, then with the response
+        assert_eq!(
+            passes(&server_bytes.lock().unwrap()),
+            40 / DEFAULT_CHUNK_WINDOW + 1
+        );
+    }
+}
@@ -1,5 +1,8 @@
+use crate::ipc::{decode, encode};
+use alloc::{boxed::Box, string::String, vec::Vec};
 use ckb_std::error::SysError;
 use core::fmt::{self, Debug, Display};
+use serde::{Deserialize, Serialize};
 
 // use core::error::Error when Rust 1.81 is used.
 pub trait Error: Debug + Display {
@@ -11,17 +14,43 @@ pub trait Error: Debug + Display {
 #[derive(Debug, Clone)]
 pub enum IpcError {
     CkbSysError(SysError),
+    /// The other end is closed at a packet boundary.
+    EndOfStream,
     UnexpectedEof,
     IncompleteVlqSeq,
     DecodeVlqOverflow,
-    ReadVlqError,
     SerializeError,
     DeserializeError,
     SliceWriteError,
+    /// `write` returned `Ok(0)` before all data was written.
+    WriteZero,
     ReadUntilError,
     ReadExactError,
-    BufReaderError,
-    ProtocolError(ProtocolErrorCode),
+    UnknownMethod(u64),
+    /// Payload length in a packet header exceeds the maximum payload size.
+    PayloadTooLarge(u64),
+    /// The server exited with a non-zero exit code.
+    ServerExit(i8),
+    /// A response doesn't match the request.
+    UnexpectedResponse,
+    /// A response answers another request, with its request id and method id.
+    ResponseMismatch {
+        request_id: u64,
+        method_id: u64,
+    },
+    /// A request of the method id arrived where it can't be served, e.g.
+    /// while waiting for a response without callbacks.
+    UnexpectedRequest(u64),
+    UnknownPacketKind(u64),
+    /// A packet of the kind arrived where it's not expected.
+    UnexpectedPacket(u64),
+    /// Both ends of a channel wait for data from each other.
+    Deadlock,
+    /// A callback through `Peer::call` on a transport which can't carry it,
+    /// e.g. a loopback.
+    CallbackUnsupported,
+    /// Error code of a response, with the details sent by the server if any.
+    ProtocolError(ProtocolErrorCode, Option<Box<ErrorDetail>>),
 }
 
 impl Display for IpcError {
@@ -39,50 +68,172 @@ impl Error for IpcError {
 /// Protocol error code used in wire protocol.
 /// Its range from 1 to 2^64 - 1.
 /// 1~20 are with same values used in syscall error.
-#[derive(Debug, Clone)]
-#[repr(u64)]
+/// Codes from `APPLICATION_ERROR_CODE_BASE` are defined by applications, see
+/// `ProtocolErrorCode::Application`. Other codes unknown to this version are
+/// decoded as `ProtocolErrorCode::Unknown`, e.g. codes from a newer server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProtocolErrorCode {
-    Ok = 0,
+    Ok,
     /// Index out of bound
-    IndexOutOfBound = 1,
+    IndexOutOfBound,
     /// Field is missing for the target
-    ItemMissing = 2,
+    ItemMissing,
     /// Buffer length is not enough, error contains actual data length
-    LengthNotEnough = 3,
+    LengthNotEnough,
     /// Data encoding error(molecule)
-    InvalidData = 4,
+    InvalidData,
     /// Failed to wait.
-    WaitFailure = 5,
+    WaitFailure,
     /// Invalid file descriptor.
-    InvalidFd = 6,
+    InvalidFd,
     /// Reading from or writing to file descriptor failed due to other end closed.
-    OtherEndClosed = 7,
+    OtherEndClosed,
     /// Max vms has been spawned.
-    MaxVmsSpawned = 8,
+    MaxVmsSpawned,
     /// Max fds has been spawned.
-    MaxFdsCreated = 9,
+    MaxFdsCreated,
 
     /// Unknown error code
-    UnknownError = 20,
+    UnknownError,
     /// Unknown error from SysError in ckb-std
-    UnknownSysError = 21,
+    UnknownSysError,
     /// Unexpected EOF
-    UnexpectedEof = 22,
+    UnexpectedEof,
     /// VQL error: incomplete VLQ sequence
-    IncompleteVlqSeq = 23,
+    IncompleteVlqSeq,
     /// VLQ error: decoding overflow
-    DecodeVlqOverflow = 24,
+    DecodeVlqOverflow,
     /// VLQ error: reading error
-    ReadVlqError = 25,
+    ReadVlqError,
     /// Serialize error
-    SerializeError = 26,
+    SerializeError,
     /// Deserialize error
-    DeserializeError = 27,
+    DeserializeError,
     /// general IO error
-    GeneralIoError = 28,
+    GeneralIoError,
+    /// Method id is not found in the service
+    UnknownMethod,
+    /// Payload exceeds the maximum payload size
+    PayloadTooLarge,
+    /// Server exited with a non-zero exit code
+    ServerExit,
+    /// Response doesn't match the request
+    UnexpectedResponse,
+    /// Request can't be served by the receiver
+    UnexpectedRequest,
+    /// Packet kind is unknown or unexpected
+    UnknownPacketKind,
+    /// Both ends wait for data from each other
+    Deadlock,
+
+    /// Application-defined error code, relative to
+    /// `APPLICATION_ERROR_CODE_BASE`. Servers can return it from `Serve::serve`
+    /// in `IpcError::ProtocolError`.
+    Application(u32),
+    /// Error code not known to this version
+    Unknown(u64),
+}
+
+/// Start of the error codes reserved for applications.
+pub const APPLICATION_ERROR_CODE_BASE: u64 = 1 << 32;
+
+impl ProtocolErrorCode {
+    /// Returns the code used in wire protocol.
+    pub fn code(&self) -> u64 {
+        match self {
+            ProtocolErrorCode::Ok => 0,
+            ProtocolErrorCode::IndexOutOfBound => 1,
+            ProtocolErrorCode::ItemMissing => 2,
+            ProtocolErrorCode::LengthNotEnough => 3,
+            ProtocolErrorCode::InvalidData => 4,
+            ProtocolErrorCode::WaitFailure => 5,
+            ProtocolErrorCode::InvalidFd => 6,
+            ProtocolErrorCode::OtherEndClosed => 7,
+            ProtocolErrorCode::MaxVmsSpawned => 8,
+            ProtocolErrorCode::MaxFdsCreated => 9,
+            ProtocolErrorCode::UnknownError => 20,
+            ProtocolErrorCode::UnknownSysError => 21,
+            ProtocolErrorCode::UnexpectedEof => 22,
+            ProtocolErrorCode::IncompleteVlqSeq => 23,
+            ProtocolErrorCode::DecodeVlqOverflow => 24,
+            ProtocolErrorCode::ReadVlqError => 25,
+            ProtocolErrorCode::SerializeError => 26,
+            ProtocolErrorCode::DeserializeError => 27,
+            ProtocolErrorCode::GeneralIoError => 28,
+            ProtocolErrorCode::UnknownMethod => 29,
+            ProtocolErrorCode::PayloadTooLarge => 30,
+            ProtocolErrorCode::ServerExit => 31,
+            ProtocolErrorCode::UnexpectedResponse => 32,
+            ProtocolErrorCode::UnexpectedRequest => 33,
+            ProtocolErrorCode::UnknownPacketKind => 34,
+            ProtocolErrorCode::Deadlock => 35,
+            ProtocolErrorCode::Application(code) => APPLICATION_ERROR_CODE_BASE + *code as u64,
+            ProtocolErrorCode::Unknown(code) => *code,
+        }
+    }
+}
+
+/// Where a request failed on the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorStage {
+    Unknown = 0,
+    ReadRequest = 1,
+    DecodeRequest = 2,
+    Serve = 3,
+    EncodeResponse = 4,
+}
+
+impl From<u8> for ErrorStage {
+    fn from(stage: u8) -> Self {
+        match stage {
+            1 => ErrorStage::ReadRequest,
+            2 => ErrorStage::DecodeRequest,
+            3 => ErrorStage::Serve,
+            4 => ErrorStage::EncodeResponse,
+            _ => ErrorStage::Unknown,
+        }
+    }
+}
 
-    // increase when appending new error codes
-    EndOfError = 29,
+/// Details of an error, carried in the payload of an error response.
+///
+/// A server can return it from `Serve::serve` in `IpcError::ProtocolError`,
+/// otherwise it's built from the error. Empty `method` and `Unknown` stage
+/// are filled in by the server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    pub message: String,
+    /// Name of the method, empty if the request can't be decoded.
+    pub method: String,
+    /// `ErrorStage` as u8
+    pub stage: u8,
+    /// Serialized application error, see `with_app_error`.
+    pub app_error: Vec<u8>,
+}
+
+impl ErrorDetail {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Attaches an application error value.
+    pub fn with_app_error<T: Serialize>(mut self, app_error: &T) -> Result<Self, IpcError> {
+        self.app_error = encode(app_error)?;
+        Ok(self)
+    }
+
+    pub fn stage(&self) -> ErrorStage {
+        ErrorStage::from(self.stage)
+    }
+
+    /// Decodes the application error value attached by `with_app_error`.
+    pub fn app_error<T: for<'de> Deserialize<'de>>(&self) -> Result<T, IpcError> {
+        decode(&self.app_error)
+    }
 }
 
 impl From<IpcError> for ProtocolErrorCode {
@@ -100,26 +251,109 @@ impl From<IpcError> for ProtocolErrorCode {
                 SysError::MaxFdsCreated => ProtocolErrorCode::MaxFdsCreated,
                 _ => ProtocolErrorCode::UnknownSysError,
             },
+            IpcError::EndOfStream => ProtocolErrorCode::OtherEndClosed,
             IpcError::UnexpectedEof => ProtocolErrorCode::UnexpectedEof,
             IpcError::IncompleteVlqSeq => ProtocolErrorCode::IncompleteVlqSeq,
             IpcError::DecodeVlqOverflow => ProtocolErrorCode::DecodeVlqOverflow,
-            IpcError::ReadVlqError => ProtocolErrorCode::ReadVlqError,
             IpcError::SerializeError => ProtocolErrorCode::SerializeError,
             IpcError::DeserializeError => ProtocolErrorCode::DeserializeError,
             IpcError::SliceWriteError
-            | IpcError::BufReaderError
+            | IpcError::WriteZero
             | IpcError::ReadUntilError
             | IpcError::ReadExactError => ProtocolErrorCode::GeneralIoError,
-            IpcError::ProtocolError(e) => e,
+            IpcError::UnknownMethod(_) => ProtocolErrorCode::UnknownMethod,
+            IpcError::PayloadTooLarge(_) => ProtocolErrorCode::PayloadTooLarge,
+            IpcError::ServerExit(_) => ProtocolErrorCode::ServerExit,
+            IpcError::UnexpectedResponse | IpcError::ResponseMismatch { .. } => {
+                ProtocolErrorCode::UnexpectedResponse
+            }
+            IpcError::UnexpectedRequest(_) | IpcError::CallbackUnsupported => {
+                ProtocolErrorCode::UnexpectedRequest
+            }
+            IpcError::UnknownPacketKind(_) | IpcError::UnexpectedPacket(_) => {
+                ProtocolErrorCode::UnknownPacketKind
+            }
+            IpcError::Deadlock => ProtocolErrorCode::Deadlock,
+            IpcError::ProtocolError(e, _) => e,
         }
     }
 }
 
 impl From<u64> for ProtocolErrorCode {
-    fn from(e: u64) -> Self {
-        if e > ProtocolErrorCode::EndOfError as u64 || (e > 9 && e < 20) {
-            panic!("Invalid protocol error code: {}", e);
+    fn from(code: u64) -> Self {
+        match code {
+            0 => ProtocolErrorCode::Ok,
+            1 => ProtocolErrorCode::IndexOutOfBound,
+            2 => ProtocolErrorCode::ItemMissing,
+            3 => ProtocolErrorCode::LengthNotEnough,
+            4 => ProtocolErrorCode::InvalidData,
+            5 => ProtocolErrorCode::WaitFailure,
+            6 => ProtocolErrorCode::InvalidFd,
+            7 => ProtocolErrorCode::OtherEndClosed,
+            8 => ProtocolErrorCode::MaxVmsSpawned,
+            9 => ProtocolErrorCode::MaxFdsCreated,
+            20 => ProtocolErrorCode::UnknownError,
+            21 => ProtocolErrorCode::UnknownSysError,
+            22 => ProtocolErrorCode::UnexpectedEof,
+            23 => ProtocolErrorCode::IncompleteVlqSeq,
+            24 => ProtocolErrorCode::DecodeVlqOverflow,
+            25 => ProtocolErrorCode::ReadVlqError,
+            26 => ProtocolErrorCode::SerializeError,
+            27 => ProtocolErrorCode::DeserializeError,
+            28 => ProtocolErrorCode::GeneralIoError,
+            29 => ProtocolErrorCode::UnknownMethod,
+            30 => ProtocolErrorCode::PayloadTooLarge,
+            31 => ProtocolErrorCode::ServerExit,
+            32 => ProtocolErrorCode::UnexpectedResponse,
+            33 => ProtocolErrorCode::UnexpectedRequest,
+            34 => ProtocolErrorCode::UnknownPacketKind,
+            35 => ProtocolErrorCode::Deadlock,
+            code if code >= APPLICATION_ERROR_CODE_BASE
+                && code - APPLICATION_ERROR_CODE_BASE <= u32::MAX as u64 =>
+            {
+                ProtocolErrorCode::Application((code - APPLICATION_ERROR_CODE_BASE) as u32)
+            }
+            code => ProtocolErrorCode::Unknown(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_detail_round_trip() {
+        let detail = ErrorDetail::new("too large")
+            .with_app_error(&42u64)
+            .unwrap();
+        let decoded: ErrorDetail = decode(&encode(&detail).unwrap()).unwrap();
+        assert_eq!(decoded, detail);
+        assert_eq!(decoded.stage(), ErrorStage::Unknown);
+        assert_eq!(decoded.app_error::<u64>().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_error_code_round_trip() {
+        for code in (0..64).chain([
+            APPLICATION_ERROR_CODE_BASE - 1,
+            APPLICATION_ERROR_CODE_BASE,
+            APPLICATION_ERROR_CODE_BASE + 1,
+            APPLICATION_ERROR_CODE_BASE + u32::MAX as u64,
+            APPLICATION_ERROR_CODE_BASE + u32::MAX as u64 + 1,
+            u64::MAX,
+        ]) {
+            assert_eq!(ProtocolErrorCode::from(code).code(), code);
         }
-        unsafe { core::mem::transmute(e) }
+        assert_eq!(ProtocolErrorCode::from(4), ProtocolErrorCode::InvalidData);
+        assert_eq!(ProtocolErrorCode::from(15), ProtocolErrorCode::Unknown(15));
+        assert_eq!(
+            ProtocolErrorCode::from(APPLICATION_ERROR_CODE_BASE + 7),
+            ProtocolErrorCode::Application(7)
+        );
+        assert_eq!(
+            ProtocolErrorCode::from(u64::MAX),
+            ProtocolErrorCode::Unknown(u64::MAX)
+        );
     }
 }
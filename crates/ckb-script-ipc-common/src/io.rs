@@ -2,7 +2,7 @@
 //! This is a shortened version of standard library's io module.
 //! Find documents from standard library.
 //!
-use crate::error::Error;
+use crate::error::{Error, IpcError};
 pub trait Read {
     type Error: Error;
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
@@ -18,13 +18,15 @@ pub trait BufRead {
 }
 
 pub trait Write {
-    type Error: Error;
+    type Error: Error + From<IpcError>;
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error>;
     fn flush(&mut self) -> Result<(), Self::Error>;
+    /// Unlike `std::io::Write::write_all`, `IpcError::WriteZero` is returned
+    /// when `write` returns `Ok(0)`.
     fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Self::Error> {
         while !buf.is_empty() {
             match self.write(buf) {
-                Ok(0) => panic!("write() returned Ok(0)"),
+                Ok(0) => return Err(IpcError::WriteZero.into()),
                 Ok(n) => buf = &buf[n..],
                 Err(e) => return Err(e),
             }
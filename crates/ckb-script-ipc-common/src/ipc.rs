@@ -1,18 +1,66 @@
+use crate::channel::Peer;
 use crate::error::IpcError;
+use crate::io::{Read, Write};
 use serde::{Deserialize, Serialize};
+use serde_molecule::{from_slice, to_vec};
+
+// used by generated code
+#[doc(hidden)]
+pub use alloc::vec::Vec;
 
 pub trait Serve {
     /// Type of request.
-    type Req: Serialize + for<'de> Deserialize<'de>;
+    type Req: Message;
 
     /// Type of response.
-    type Resp: Serialize + for<'de> Deserialize<'de>;
+    type Resp: Message;
 
     /// Responds to a single request.
     fn serve(&mut self, req: Self::Req) -> Result<Self::Resp, IpcError>;
 
+    /// Responds to a single request, with the other end of the channel to
+    /// call back while serving it. Defaults to `serve`.
+    fn serve_with_peer<R, W>(
+        &mut self,
+        req: Self::Req,
+        _peer: &mut Peer<'_, R, W>,
+    ) -> Result<Self::Resp, IpcError>
+    where
+        R: Read<Error = IpcError>,
+        W: Write<Error = IpcError>,
+    {
+        self.serve(req)
+    }
+
     /// Extracts a method name from the request.
     fn method(&self, _request: &Self::Req) -> Option<&'static str> {
         None
     }
 }
+
+/// Requests and responses of a service.
+///
+/// The method is carried by `method_id` in packets, so a payload only
+/// contains the arguments of a request, or the return value of a response.
+pub trait Message: Sized {
+    /// Id of the method, unique within a service.
+    fn method_id(&self) -> u64;
+
+    /// Serializes the payload, without the method.
+    fn encode(&self) -> Result<Vec<u8>, IpcError>;
+
+    /// Deserializes the payload of method `method_id`.
+    /// Returns `IpcError::UnknownMethod` without looking into the payload
+    /// when the method doesn't exist.
+    fn decode(method_id: u64, payload: &[u8]) -> Result<Self, IpcError>;
+}
+
+/// Serializes a value into a payload.
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, IpcError> {
+    to_vec(value, false).map_err(|_| IpcError::SerializeError)
+}
+
+/// Deserializes a value from a payload.
+pub fn decode<T: for<'de> Deserialize<'de>>(payload: &[u8]) -> Result<T, IpcError> {
+    from_slice(payload, false).map_err(|_| IpcError::DeserializeError)
+}
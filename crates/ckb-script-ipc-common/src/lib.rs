@@ -1,13 +1,21 @@
 #![no_std]
 extern crate alloc;
 pub mod bufreader;
+pub mod bufwriter;
 pub mod channel;
+pub mod duplex;
 pub mod error;
 pub mod io;
 pub mod io_impl;
 pub mod ipc;
+pub mod loopback;
 pub mod packet;
 pub mod pipe;
+pub mod server;
 pub mod spawn;
+pub mod stream;
+#[cfg(test)]
+mod test_utils;
+pub mod upload;
 pub mod utils;
 pub mod vlq;
@@ -0,0 +1,386 @@
+//!
+//! In-process transport, running a service in the same VM as its client.
+//!
+//! Requests and responses still go through the full packet encoding and
+//! decoding path, so it can be used to unit test a service natively, or to
+//! link a service directly into a script instead of paying cycles to spawn it.
+//!
+//! Requests are served lazily, when the client waits for a reply, so the
+//! client can't reply to the service while a request is served. Notifications
+//! are served as soon as they're written, as no reply follows them:
+//!
+//! - responses are sent in chunks of the default size, passing the turn every
+//!   default window of chunks, which clients pipelining requests must be
+//!   able to buffer, see `duplex`;
+//! - uploads are buffered until the client finishes them, then served at once;
+//! - streams are sent in full, without waiting for credit, so cancelling a
+//!   stream only skips the items;
+//! - calling back the client through `Peer::call` fails with
+//!   `IpcError::CallbackUnsupported`.
+//!
+use crate::channel::Channel;
+use crate::duplex::{
+    read_chunk, serialize_chunk, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_WINDOW, MORE, PASS,
+};
+use crate::error::IpcError;
+use crate::io::{Read, Write};
+use crate::ipc::Serve;
+use crate::packet::{Frame, DEFAULT_MAX_PAYLOAD_SIZE};
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+struct Inner<S> {
+    serve: S,
+    // chunks written by the client
+    requests: VecDeque<u8>,
+    // data of the chunks, not served yet
+    pending: Vec<u8>,
+    // flags of the last chunk, until the turn is passed back
+    flags: Option<u64>,
+    responses: VecDeque<u8>,
+}
+
+impl<S: Serve> Inner<S> {
+    // Moves the data of the chunks written so far into `pending`.
+    fn receive(&mut self) -> Result<(), IpcError> {
+        while let Some((data, flags)) = read_chunk(&mut self.requests, DEFAULT_MAX_PAYLOAD_SIZE)? {
+            self.pending.extend(data);
+            self.flags = Some(flags);
+        }
+        Ok(())
+    }
+
+    // Serves the complete notifications at the start of `pending`, e.g. the
+    // last thing written by a script, which never waits for a reply. They
+    // have no response, and their errors can only be logged.
+    fn serve_notifications(&mut self) -> Result<(), IpcError> {
+        self.receive()?;
+        let mut len = 0;
+        let mut rest = &self.pending[..];
+        while let Ok(Frame::Request(packet)) = Frame::read_from(&mut rest) {
+            if !packet.is_notification() {
+                break;
+            }
+            len = self.pending.len() - rest.len();
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        // dropped first, so that they aren't served again
+        let notifications: Vec<u8> = self.pending.drain(..len).collect();
+        let mut channel = Channel::new(&notifications[..], Vec::new())
+            .without_flow_control()
+            .for_loopback();
+        while channel.serve_next(&mut self.serve)? {}
+        Ok(())
+    }
+
+    // Serves the requests written so far once the client waits for a reply,
+    // and passes the turn back to the client. Responses sent before an error,
+    // including the error response, are still passed to the client, and the
+    // requests are dropped so that they aren't served again.
+    fn serve_pending(&mut self) -> Result<(), IpcError> {
+        self.receive()?;
+        let flags = match self.flags.take() {
+            Some(flags) => flags,
+            None => return Ok(()),
+        };
+        let mut output = Vec::new();
+        let mut result = Ok(());
+        // the client passing the turn only because its window is exhausted
+        // keeps writing, e.g. an upload
+        if flags & MORE == 0 {
+            let Inner { serve, pending, .. } = self;
+            let mut channel = Channel::new(&pending[..], &mut output)
+                .without_flow_control()
+                .for_loopback();
+            result = loop {
+                match channel.serve_next(serve) {
+                    Ok(true) => {}
+                    Ok(false) => break Ok(()),
+                    Err(e) => break Err(e),
+                }
+            };
+            pending.clear();
+        }
+        // The turn is passed every window of chunks too, as the client buffers
+        // at most that while waiting for it. The client passes it back before
+        // reading further, the chunks left are already queued then.
+        let mut chunks = output.chunks(DEFAULT_CHUNK_SIZE).enumerate().peekable();
+        if chunks.peek().is_none() {
+            self.responses.extend(serialize_chunk(&[], PASS));
+        }
+        while let Some((i, chunk)) = chunks.next() {
+            let flags = if chunks.peek().is_none() {
+                PASS
+            } else if (i + 1) % DEFAULT_CHUNK_WINDOW == 0 {
+                PASS | MORE
+            } else {
+                0
+            };
+            self.responses.extend(serialize_chunk(chunk, flags));
+        }
+        result
+    }
+}
+
+/// Reading end of a loopback transport, responses are read from it.
+pub struct LoopbackReader<S> {
+    inner: Rc<RefCell<Inner<S>>>,
+}
+
+/// Writing end of a loopback transport, requests are written into it.
+pub struct LoopbackWriter<S> {
+    inner: Rc<RefCell<Inner<S>>>,
+}
+
+/// Creates a loopback transport to `serve`. Requests written into the writer
+/// are served when the reader runs out of responses, notifications as soon as
+/// they're written.
+///
+/// ```ignore
+/// let (reader, writer) = loopback(WorldServer.server());
+/// let mut client = WorldClient::new(reader, writer);
+/// ```
+pub fn loopback<S: Serve>(serve: S) -> (LoopbackReader<S>, LoopbackWriter<S>) {
+    let inner = Rc::new(RefCell::new(Inner {
+        serve,
+        requests: VecDeque::new(),
+        pending: Vec::new(),
+        flags: None,
+        responses: VecDeque::new(),
+    }));
+    (
+        LoopbackReader {
+            inner: inner.clone(),
+        },
+        LoopbackWriter { inner },
+    )
+}
+
+impl<S: Serve> Read for LoopbackReader<S> {
+    type Error = IpcError;
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut inner = self.inner.borrow_mut();
+        if inner.responses.is_empty() {
+            // an error is reported to the client by the error response sent
+            // with it, if any
+            if let Err(e) = inner.serve_pending() {
+                if inner.responses.is_empty() {
+                    return Err(e);
+                }
+            }
+        }
+        inner.responses.read(buf)
+    }
+}
+
+impl<S: Serve> Write for LoopbackWriter<S> {
+    type Error = IpcError;
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let mut inner = self.inner.borrow_mut();
+        let n = inner.requests.write(buf)?;
+        inner.serve_notifications()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duplex::Then;
+    use crate::error::ProtocolErrorCode;
+    use crate::packet::RequestPacket;
+    use crate::test_utils::{
+        AddRequest, AddResponse, Adder, CallingBack, FillResponse, Filling, Notifying, Range,
+        Summing, Totaling, ADD,
+    };
+    use alloc::vec;
+    use core::cell::Cell;
+
+    #[test]
+    fn test_loopback_call() {
+        let (reader, writer) = loopback(Adder);
+        let mut channel = Channel::new(reader, writer);
+        let resp: AddResponse = channel
+            .call("Adder.add", AddRequest { a: 1, b: 2 })
+            .unwrap();
+        assert_eq!(resp.0, 3);
+        assert!(matches!(
+            channel.call::<_, AddResponse>("Adder.add", AddRequest { a: u64::MAX, b: 1 }),
+            Err(IpcError::ProtocolError(ProtocolErrorCode::InvalidData, _))
+        ));
+        let resp: AddResponse = channel
+            .call("Adder.add", AddRequest { a: 3, b: 4 })
+            .unwrap();
+        assert_eq!(resp.0, 7);
+    }
+
+    #[test]
+    fn test_loopback_chunks() {
+        // requests spanning several chunks are served once complete
+        let (reader, writer) = loopback(Adder);
+        let mut channel = Channel::new(reader, writer).with_chunk_size(2);
+        let resp: AddResponse = channel
+            .call("Adder.add", AddRequest { a: 1, b: 2 })
+            .unwrap();
+        assert_eq!(resp.0, 3);
+        let resp: AddResponse = channel
+            .call("Adder.add", AddRequest { a: 3, b: 4 })
+            .unwrap();
+        assert_eq!(resp.0, 7);
+    }
+
+    #[test]
+    fn test_loopback_notification() {
+        // served as soon as written, even if nothing is read afterwards
+        let total = Rc::new(Cell::new(0));
+        let (reader, writer) = loopback(Totaling(total.clone()));
+        let mut channel = Channel::new(reader, writer).with_chunk_size(2);
+        channel
+            .send_notification(AddRequest { a: 1, b: 2 })
+            .unwrap();
+        assert_eq!(total.get(), 3);
+        let resp: AddResponse = channel.call("Totaling", AddRequest { a: 3, b: 4 }).unwrap();
+        assert_eq!(resp.0, 10);
+        channel
+            .send_notification(AddRequest { a: 5, b: 6 })
+            .unwrap();
+        drop(channel);
+        assert_eq!(total.get(), 21);
+    }
+
+    #[test]
+    fn test_loopback_pipelined() {
+        // the first response spans more than a window of chunks
+        let (reader, writer) = loopback(Filling);
+        let mut channel = Channel::new(reader, writer);
+        let first = channel
+            .send_request(AddRequest { a: 40 * 1024, b: 1 })
+            .unwrap();
+        let second = channel.send_request(AddRequest { a: 10, b: 2 }).unwrap();
+        channel
+            .send_notification(AddRequest { a: 10, b: 3 })
+            .unwrap();
+        let resp: FillResponse = channel.receive_response(first, ADD).unwrap();
+        assert_eq!(resp.0, vec![1; 40 * 1024]);
+        let resp: FillResponse = channel.receive_response(second, ADD).unwrap();
+        assert_eq!(resp.0, vec![2; 10]);
+    }
+
+    #[test]
+    fn test_loopback_without_request() {
+        let (reader, writer) = loopback(Adder);
+        let mut channel = Channel::new(reader, writer);
+        assert!(matches!(
+            channel.receive_response::<AddResponse>(1, ADD),
+            Err(IpcError::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn test_loopback_stream() {
+        // longer than the credit granted at once
+        let (reader, writer) = loopback(Range);
+        let mut channel = Channel::new(reader, writer);
+        let mut stream = channel
+            .call_stream_with_window::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 40 }, 4)
+            .unwrap();
+        let items: Result<Vec<u64>, IpcError> = (&mut stream).collect();
+        assert_eq!(items.unwrap(), (0..40).collect::<Vec<u64>>());
+        assert_eq!(stream.finish().unwrap().0, 1);
+        // the items left are skipped, and the credits granted meanwhile too
+        let mut stream = channel
+            .call_stream_with_window::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 40 }, 4)
+            .unwrap();
+        assert_eq!(stream.next().unwrap().unwrap(), 0);
+        assert_eq!(stream.finish().unwrap().0, 1);
+        let items: Result<Vec<u64>, IpcError> = channel
+            .call_stream::<_, u64, AddResponse>("Range", AddRequest { a: 5, b: 8 })
+            .unwrap()
+            .collect();
+        assert_eq!(items.unwrap(), [5, 6, 7]);
+    }
+
+    #[test]
+    fn test_loopback_upload() {
+        // spanning several windows of chunks
+        let (reader, writer) = loopback(Summing);
+        let mut channel = Channel::new(reader, writer).with_chunk_size(16);
+        let data = [1u8; 1000];
+        let mut sink = channel
+            .call_upload_with_chunk_size::<_, AddResponse>(
+                "Summing",
+                AddRequest { a: 2000, b: 0 },
+                7,
+            )
+            .unwrap();
+        sink.write_all(&data).unwrap();
+        assert_eq!(sink.finish().unwrap().0, 1000);
+        let resp: AddResponse = channel
+            .call_upload::<_, AddResponse>("Summing", AddRequest { a: 2000, b: 0 })
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(resp.0, 0);
+    }
+
+    #[test]
+    fn test_loopback_callback() {
+        let (reader, writer) = loopback(CallingBack);
+        let mut channel = Channel::new(reader, writer);
+        assert!(matches!(
+            channel.call_with_callbacks::<_, AddResponse, _>(
+                "CallingBack",
+                AddRequest { a: 1, b: 2 },
+                &mut Adder
+            ),
+            Err(IpcError::ProtocolError(
+                ProtocolErrorCode::UnexpectedRequest,
+                Some(detail)
+            )) if detail.message == "CallbackUnsupported"
+        ));
+        // notifications from the server can't be carried either
+        let (reader, writer) = loopback(Notifying);
+        let mut channel = Channel::new(reader, writer);
+        assert!(matches!(
+            channel.call_with_callbacks::<_, AddResponse, _>(
+                "Notifying",
+                AddRequest { a: 1, b: 2 },
+                &mut Adder
+            ),
+            Err(IpcError::ProtocolError(
+                ProtocolErrorCode::UnexpectedRequest,
+                Some(detail)
+            )) if detail.message == "CallbackUnsupported"
+        ));
+    }
+
+    #[test]
+    fn test_loopback_payload_too_large() {
+        // the error response is received, and the request isn't served again
+        let (reader, writer) = loopback(Adder);
+        let mut channel = Channel::new(reader, writer);
+        let payload = vec![0; DEFAULT_MAX_PAYLOAD_SIZE + 1];
+        channel
+            .write_packet(&RequestPacket::new(100, ADD, payload), Then::Read)
+            .unwrap();
+        assert!(matches!(
+            channel.receive_response::<AddResponse>(100, ADD),
+            Err(IpcError::ProtocolError(
+                ProtocolErrorCode::PayloadTooLarge,
+                _
+            ))
+        ));
+        let resp: AddResponse = channel
+            .call("Adder.add", AddRequest { a: 1, b: 2 })
+            .unwrap();
+        assert_eq!(resp.0, 3);
+    }
+}
@@ -1,30 +1,64 @@
-use alloc::vec;
 use alloc::vec::Vec;
+use core::cmp;
 use core::fmt::{Debug, Formatter, Result as FmtResult};
 use hex;
 
 use crate::utils::read_exact;
-use crate::vlq::{vlq_decode, vlq_encode};
+use crate::vlq::{vlq_decode, vlq_encode_into, MAX_VLQ_LEN};
 use crate::{error::IpcError, io::Read};
 
+/// Default maximum payload size accepted when reading a packet.
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 1024 * 1024;
+
+// initial size of the buffer receiving a payload
+const PAYLOAD_CHUNK_SIZE: usize = 1024;
+
+// packet kinds, following the version in every packet, so either end of a
+// channel can tell requests from responses
+const KIND_REQUEST: u64 = 0;
+const KIND_RESPONSE: u64 = 1;
+const KIND_STREAM_ITEM: u64 = 2;
+const KIND_STREAM_CREDIT: u64 = 3;
+
 pub trait Packet {
     fn version(&self) -> u8;
     fn payload(&self) -> &[u8];
-    fn read_from<R: Read>(reader: &mut R) -> Result<Self, IpcError>
+    /// Reads a packet with a payload up to `DEFAULT_MAX_PAYLOAD_SIZE` bytes.
+    fn read_from<R: Read<Error = IpcError>>(reader: &mut R) -> Result<Self, IpcError>
+    where
+        Self: Sized,
+    {
+        Self::read_from_with_limit(reader, DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+    /// Reads a packet. Returns `IpcError::PayloadTooLarge` when the payload
+    /// length in header exceeds `max_payload_size`.
+    fn read_from_with_limit<R: Read<Error = IpcError>>(
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Self, IpcError>
     where
         Self: Sized;
     fn serialize(&self) -> Vec<u8>;
 }
 
+/// Request to a method. A request with request id 0 is a notification, which
+/// is served without a response.
 pub struct RequestPacket {
     version: u8,
+    request_id: u64,
     method_id: u64,
     payload: Vec<u8>,
 }
 
 impl Debug for RequestPacket {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "RequestPacket, payload: {}", hex::encode(&self.payload))
+        write!(
+            f,
+            "RequestPacket, request_id: {}, method_id: {}, payload: {}",
+            self.request_id,
+            self.method_id,
+            hex::encode(&self.payload)
+        )
     }
 }
 
@@ -35,43 +69,58 @@ impl Packet for RequestPacket {
     fn payload(&self) -> &[u8] {
         &self.payload
     }
-    fn read_from<R: Read>(reader: &mut R) -> Result<Self, IpcError> {
-        let version = read_next_vlq(reader)? as u8;
-        let method_id = read_next_vlq(reader)?;
-        let payload_length = read_next_vlq(reader)?;
-        let mut payload = vec![0u8; payload_length as usize];
-        read_exact(reader, &mut payload[..])?;
-        Ok(RequestPacket {
-            version,
-            method_id,
-            payload,
-        })
+    fn read_from_with_limit<R: Read<Error = IpcError>>(
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Self, IpcError> {
+        match Frame::read_from_with_limit(reader, max_payload_size)? {
+            Frame::Request(packet) => Ok(packet),
+            Frame::Response(_) => Err(IpcError::UnexpectedResponse),
+            frame => Err(IpcError::UnexpectedPacket(frame.kind())),
+        }
     }
     fn serialize(&self) -> Vec<u8> {
-        let mut buf = vec![];
-        buf.extend_from_slice(&vlq_encode(self.version as u64));
-        buf.extend_from_slice(&vlq_encode(self.method_id));
-        buf.extend_from_slice(&vlq_encode(self.payload.len() as u64));
-        buf.extend_from_slice(&self.payload);
-        buf
+        serialize_frame(
+            &[
+                self.version as u64,
+                KIND_REQUEST,
+                self.request_id,
+                self.method_id,
+                self.payload.len() as u64,
+            ],
+            &self.payload,
+        )
     }
 }
 
 impl RequestPacket {
-    pub fn new(payload: Vec<u8>) -> Self {
+    pub fn new(request_id: u64, method_id: u64, payload: Vec<u8>) -> Self {
         Self {
             version: 0,
-            method_id: 0,
+            request_id,
+            method_id,
             payload,
         }
     }
+    /// Id of the request, chosen by the client and echoed in its response.
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+    pub fn is_notification(&self) -> bool {
+        self.request_id == 0
+    }
     pub fn method_id(&self) -> u64 {
         self.method_id
     }
 }
 
+/// Response to a request, echoing its request id and method id. Both are 0 in
+/// an error response which doesn't answer a particular request, e.g. when the
+/// server can't read a request.
 pub struct ResponsePacket {
     version: u8,
+    request_id: u64,
+    method_id: u64,
     error_code: u64,
     payload: Vec<u8>,
 }
@@ -80,7 +129,9 @@ impl Debug for ResponsePacket {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         write!(
             f,
-            "ResponsePacket, error_code: {}, payload: {}",
+            "ResponsePacket, request_id: {}, method_id: {}, error_code: {}, payload: {}",
+            self.request_id,
+            self.method_id,
             self.error_code,
             hex::encode(&self.payload)
         )
@@ -94,53 +145,454 @@ impl Packet for ResponsePacket {
     fn payload(&self) -> &[u8] {
         &self.payload
     }
-    fn read_from<R: Read>(reader: &mut R) -> Result<Self, IpcError> {
-        let version = read_next_vlq(reader)? as u8;
-        let error_code = read_next_vlq(reader)?;
-        let payload_length = read_next_vlq(reader)?;
-        let mut payload = vec![0u8; payload_length as usize];
-        read_exact(reader, &mut payload[..])?;
-        Ok(ResponsePacket {
-            version,
-            error_code,
-            payload,
-        })
+    fn read_from_with_limit<R: Read<Error = IpcError>>(
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Self, IpcError> {
+        match Frame::read_from_with_limit(reader, max_payload_size)? {
+            Frame::Request(packet) => Err(IpcError::UnexpectedRequest(packet.method_id())),
+            Frame::Response(packet) => Ok(packet),
+            frame => Err(IpcError::UnexpectedPacket(frame.kind())),
+        }
     }
     fn serialize(&self) -> Vec<u8> {
-        let mut buf = vec![];
-        buf.extend_from_slice(&vlq_encode(self.version as u64));
-        buf.extend_from_slice(&vlq_encode(self.error_code));
-        buf.extend_from_slice(&vlq_encode(self.payload.len() as u64));
-        buf.extend_from_slice(&self.payload);
-        buf
+        serialize_frame(
+            &[
+                self.version as u64,
+                KIND_RESPONSE,
+                self.request_id,
+                self.method_id,
+                self.error_code,
+                self.payload.len() as u64,
+            ],
+            &self.payload,
+        )
     }
 }
 
 impl ResponsePacket {
-    pub fn new(error_code: u64, payload: Vec<u8>) -> Self {
+    pub fn new(request_id: u64, method_id: u64, error_code: u64, payload: Vec<u8>) -> Self {
         Self {
             version: 0,
+            request_id,
+            method_id,
             error_code,
             payload,
         }
     }
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+    pub fn method_id(&self) -> u64 {
+        self.method_id
+    }
     pub fn error_code(&self) -> u64 {
         self.error_code
     }
 }
 
-pub fn read_next_vlq(reader: &mut impl Read) -> Result<u64, IpcError> {
+/// Item of a streaming response, sent by the server as long as it has credit
+/// from the client. The stream ends with the response to the request.
+///
+/// Also a chunk of an upload, sent by the client after its request. The upload
+/// ends with an empty chunk.
+pub struct StreamItemPacket {
+    version: u8,
+    request_id: u64,
+    payload: Vec<u8>,
+}
+
+impl Debug for StreamItemPacket {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "StreamItemPacket, request_id: {}, payload: {}",
+            self.request_id,
+            hex::encode(&self.payload)
+        )
+    }
+}
+
+impl Packet for StreamItemPacket {
+    fn version(&self) -> u8 {
+        self.version
+    }
+    fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+    fn read_from_with_limit<R: Read<Error = IpcError>>(
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Self, IpcError> {
+        match Frame::read_from_with_limit(reader, max_payload_size)? {
+            Frame::StreamItem(packet) => Ok(packet),
+            frame => Err(IpcError::UnexpectedPacket(frame.kind())),
+        }
+    }
+    fn serialize(&self) -> Vec<u8> {
+        serialize_frame(
+            &[
+                self.version as u64,
+                KIND_STREAM_ITEM,
+                self.request_id,
+                self.payload.len() as u64,
+            ],
+            &self.payload,
+        )
+    }
+}
+
+impl StreamItemPacket {
+    pub fn new(request_id: u64, payload: Vec<u8>) -> Self {
+        Self {
+            version: 0,
+            request_id,
+            payload,
+        }
+    }
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+/// Credit granted by the client of a streaming response: the number of items
+/// the server can send further. A credit of 0 cancels the stream.
+#[derive(Debug)]
+pub struct StreamCreditPacket {
+    version: u8,
+    request_id: u64,
+    credit: u64,
+}
+
+impl Packet for StreamCreditPacket {
+    fn version(&self) -> u8 {
+        self.version
+    }
+    fn payload(&self) -> &[u8] {
+        &[]
+    }
+    fn read_from_with_limit<R: Read<Error = IpcError>>(
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Self, IpcError> {
+        match Frame::read_from_with_limit(reader, max_payload_size)? {
+            Frame::StreamCredit(packet) => Ok(packet),
+            frame => Err(IpcError::UnexpectedPacket(frame.kind())),
+        }
+    }
+    fn serialize(&self) -> Vec<u8> {
+        serialize_frame(
+            &[
+                self.version as u64,
+                KIND_STREAM_CREDIT,
+                self.request_id,
+                self.credit,
+            ],
+            &[],
+        )
+    }
+}
+
+impl StreamCreditPacket {
+    pub fn new(request_id: u64, credit: u64) -> Self {
+        Self {
+            version: 0,
+            request_id,
+            credit,
+        }
+    }
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+    pub fn credit(&self) -> u64 {
+        self.credit
+    }
+}
+
+/// A packet of any kind, as read by an end which can receive both requests
+/// and responses.
+#[derive(Debug)]
+pub enum Frame {
+    Request(RequestPacket),
+    Response(ResponsePacket),
+    StreamItem(StreamItemPacket),
+    StreamCredit(StreamCreditPacket),
+}
+
+impl Frame {
+    /// Kind of the packet, as in its header.
+    pub fn kind(&self) -> u64 {
+        match self {
+            Frame::Request(_) => KIND_REQUEST,
+            Frame::Response(_) => KIND_RESPONSE,
+            Frame::StreamItem(_) => KIND_STREAM_ITEM,
+            Frame::StreamCredit(_) => KIND_STREAM_CREDIT,
+        }
+    }
+    /// Reads a packet with a payload up to `DEFAULT_MAX_PAYLOAD_SIZE` bytes.
+    pub fn read_from<R: Read<Error = IpcError>>(reader: &mut R) -> Result<Self, IpcError> {
+        Self::read_from_with_limit(reader, DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+    /// Reads a packet of either kind, see `Packet::read_from_with_limit`.
+    pub fn read_from_with_limit<R: Read<Error = IpcError>>(
+        reader: &mut R,
+        max_payload_size: usize,
+    ) -> Result<Self, IpcError> {
+        let version = read_version(reader)?;
+        match read_next_vlq(reader)? {
+            KIND_REQUEST => {
+                let request_id = read_next_vlq(reader)?;
+                let method_id = read_next_vlq(reader)?;
+                let payload_length = read_next_vlq(reader)?;
+                let payload = read_payload(reader, payload_length, max_payload_size)?;
+                Ok(Frame::Request(RequestPacket {
+                    version,
+                    request_id,
+                    method_id,
+                    payload,
+                }))
+            }
+            KIND_RESPONSE => {
+                let request_id = read_next_vlq(reader)?;
+                let method_id = read_next_vlq(reader)?;
+                let error_code = read_next_vlq(reader)?;
+                let payload_length = read_next_vlq(reader)?;
+                let payload = read_payload(reader, payload_length, max_payload_size)?;
+                Ok(Frame::Response(ResponsePacket {
+                    version,
+                    request_id,
+                    method_id,
+                    error_code,
+                    payload,
+                }))
+            }
+            KIND_STREAM_ITEM => {
+                let request_id = read_next_vlq(reader)?;
+                let payload_length = read_next_vlq(reader)?;
+                let payload = read_payload(reader, payload_length, max_payload_size)?;
+                Ok(Frame::StreamItem(StreamItemPacket {
+                    version,
+                    request_id,
+                    payload,
+                }))
+            }
+            KIND_STREAM_CREDIT => {
+                let request_id = read_next_vlq(reader)?;
+                let credit = read_next_vlq(reader)?;
+                Ok(Frame::StreamCredit(StreamCreditPacket {
+                    version,
+                    request_id,
+                    credit,
+                }))
+            }
+            kind => Err(IpcError::UnknownPacketKind(kind)),
+        }
+    }
+}
+
+/// Assembles a frame, which is the header fields in VLQ followed by the
+/// payload, with a single allocation.
+fn serialize_frame(header: &[u64], payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(header.len() * MAX_VLQ_LEN + payload.len());
+    for field in header {
+        vlq_encode_into(*field, &mut buf);
+    }
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Reads a payload of `length` bytes. The length comes from the other end and
+/// can't be trusted, so the buffer only grows as data is actually received.
+pub(crate) fn read_payload(
+    reader: &mut impl Read<Error = IpcError>,
+    length: u64,
+    max_payload_size: usize,
+) -> Result<Vec<u8>, IpcError> {
+    if length > max_payload_size as u64 {
+        return Err(IpcError::PayloadTooLarge(length));
+    }
+    let length = length as usize;
+    let mut payload = Vec::new();
+    while payload.len() < length {
+        let start = payload.len();
+        let chunk = cmp::min(cmp::max(start, PAYLOAD_CHUNK_SIZE), length - start);
+        payload.reserve_exact(chunk);
+        payload.resize(start + chunk, 0);
+        read_exact(reader, &mut payload[start..])?;
+    }
+    Ok(payload)
+}
+
+/// Reads the version, which is the first field of a packet. Reaching the end
+/// of the reader here is a clean end of stream, rather than a truncated packet.
+fn read_version(reader: &mut impl Read<Error = IpcError>) -> Result<u8, IpcError> {
+    match read_next_vlq(reader) {
+        Ok(version) => Ok(version as u8),
+        Err(IpcError::UnexpectedEof) => Err(IpcError::EndOfStream),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads a VLQ from the reader. Returns `IpcError::UnexpectedEof` if the reader
+/// is at its end before the first byte. At most `MAX_VLQ_LEN` bytes are read,
+/// a longer sequence fails with `IpcError::DecodeVlqOverflow`. Errors of the
+/// reader are returned unchanged.
+pub fn read_next_vlq(reader: &mut impl Read<Error = IpcError>) -> Result<u64, IpcError> {
     let mut peek = [0u8; 1];
-    let mut buf = vec![];
-    loop {
-        let n = reader.read(&mut peek).map_err(|_| IpcError::ReadVlqError)?;
+    let mut buf = [0u8; MAX_VLQ_LEN];
+    let mut len = 0;
+    while len < MAX_VLQ_LEN {
+        let n = reader.read(&mut peek)?;
         if n == 0 {
+            if len == 0 {
+                return Err(IpcError::UnexpectedEof);
+            }
             break;
         }
-        buf.push(peek[0]);
+        buf[len] = peek[0];
+        len += 1;
         if peek[0] & 0x80 == 0 {
             break;
         }
     }
-    vlq_decode(&buf)
+    vlq_decode(&buf[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_read_end_of_stream() {
+        let mut reader: &[u8] = &[];
+        assert!(matches!(
+            RequestPacket::read_from(&mut reader),
+            Err(IpcError::EndOfStream)
+        ));
+        let mut reader: &[u8] = &[];
+        assert!(matches!(
+            ResponsePacket::read_from(&mut reader),
+            Err(IpcError::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn test_read_truncated_packet() {
+        let bytes = RequestPacket::new(7, 1, vec![1, 2, 3]).serialize();
+        for len in 1..bytes.len() {
+            let mut reader = &bytes[..len];
+            let result = RequestPacket::read_from(&mut reader);
+            assert!(result.is_err());
+            assert!(!matches!(result, Err(IpcError::EndOfStream)));
+        }
+        let mut reader = &bytes[..];
+        let packet = RequestPacket::read_from(&mut reader).unwrap();
+        assert_eq!(packet.request_id(), 7);
+        assert_eq!(packet.method_id(), 1);
+        assert_eq!(packet.payload(), &[1, 2, 3]);
+        assert!(matches!(
+            RequestPacket::read_from(&mut reader),
+            Err(IpcError::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn test_read_payload_too_large() {
+        let bytes = RequestPacket::new(1, 1, vec![0u8; 100]).serialize();
+        assert!(matches!(
+            RequestPacket::read_from_with_limit(&mut &bytes[..], 99),
+            Err(IpcError::PayloadTooLarge(100))
+        ));
+        let packet = RequestPacket::read_from_with_limit(&mut &bytes[..], 100).unwrap();
+        assert_eq!(packet.payload().len(), 100);
+
+        // a header claiming a huge payload is rejected before reading it
+        let mut bytes = vec![];
+        for field in [0, KIND_RESPONSE, 0, 0, 0, u64::MAX] {
+            vlq_encode_into(field, &mut bytes);
+        }
+        assert!(matches!(
+            ResponsePacket::read_from(&mut &bytes[..]),
+            Err(IpcError::PayloadTooLarge(u64::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_read_vlq_overflow() {
+        // a peer sending continuation bytes forever is cut off
+        let bytes = [0x80u8; 11];
+        let mut reader = &bytes[..];
+        assert!(matches!(
+            read_next_vlq(&mut reader),
+            Err(IpcError::DecodeVlqOverflow)
+        ));
+        assert_eq!(reader.len(), 1);
+        assert!(matches!(
+            Frame::read_from(&mut &bytes[..]),
+            Err(IpcError::DecodeVlqOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_read_payload_in_chunks() {
+        let payload: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let bytes = ResponsePacket::new(1, 1, 0, payload.clone()).serialize();
+        let packet = ResponsePacket::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(packet.payload(), &payload[..]);
+
+        // truncated payload
+        let mut reader = &bytes[..bytes.len() - 1];
+        assert!(ResponsePacket::read_from(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_read_frame() {
+        let mut bytes = RequestPacket::new(1, 2, vec![3]).serialize();
+        bytes.extend(ResponsePacket::new(1, 2, 0, vec![4]).serialize());
+        let mut reader = &bytes[..];
+        assert!(matches!(
+            Frame::read_from(&mut reader),
+            Ok(Frame::Request(p)) if p.request_id() == 1 && p.payload() == [3]
+        ));
+        assert!(matches!(
+            Frame::read_from(&mut reader),
+            Ok(Frame::Response(p)) if p.request_id() == 1 && p.payload() == [4]
+        ));
+        assert!(matches!(
+            Frame::read_from(&mut reader),
+            Err(IpcError::EndOfStream)
+        ));
+
+        // packets of the other kind are rejected
+        assert!(matches!(
+            ResponsePacket::read_from(&mut &bytes[..]),
+            Err(IpcError::UnexpectedRequest(2))
+        ));
+        let bytes = StreamCreditPacket::new(1, 5).serialize();
+        assert!(matches!(
+            Frame::read_from(&mut &bytes[..]),
+            Ok(Frame::StreamCredit(p)) if p.request_id() == 1 && p.credit() == 5
+        ));
+        assert!(matches!(
+            RequestPacket::read_from(&mut &bytes[..]),
+            Err(IpcError::UnexpectedPacket(KIND_STREAM_CREDIT))
+        ));
+        let bytes = StreamItemPacket::new(1, vec![6]).serialize();
+        assert!(matches!(
+            StreamItemPacket::read_from(&mut &bytes[..]),
+            Ok(p) if p.request_id() == 1 && p.payload() == [6]
+        ));
+        let mut bytes = vec![];
+        for field in [0, 7] {
+            vlq_encode_into(field, &mut bytes);
+        }
+        assert!(matches!(
+            Frame::read_from(&mut &bytes[..]),
+            Err(IpcError::UnknownPacketKind(7))
+        ));
+    }
 }
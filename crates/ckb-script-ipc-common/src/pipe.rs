@@ -1,7 +1,10 @@
 use crate::error::IpcError;
 use crate::io::{Read, Write};
-use ckb_std::syscalls::{read, write};
+use ckb_std::error::SysError;
+use ckb_std::syscalls::{close, read, write};
+use core::mem;
 
+/// Owned pipe fd, which is closed when dropped.
 pub struct Pipe {
     id: u64,
 }
@@ -22,6 +25,26 @@ impl Pipe {
     pub fn writable(&self) -> bool {
         self.id % 2 == 1
     }
+
+    /// Releases the ownership of the fd, which is no longer closed on drop.
+    /// Used e.g. when the fd is passed to a spawned process.
+    pub fn into_raw(self) -> u64 {
+        let id = self.id;
+        mem::forget(self);
+        id
+    }
+
+    /// Closes the fd, reporting the error `Drop` would ignore.
+    pub fn close(self) -> Result<(), IpcError> {
+        close(self.into_raw()).map_err(IpcError::CkbSysError)
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // nothing to do on error: the fd is either already closed or invalid
+        let _ = close(self.id);
+    }
 }
 
 impl From<u64> for Pipe {
@@ -35,6 +58,9 @@ impl Read for Pipe {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
         match read(self.id, buf) {
             Ok(n) => Ok(n),
+            // Same as `std::io::Read`, reading from a pipe whose other end is
+            // closed returns 0.
+            Err(SysError::OtherEndClosed) => Ok(0),
             Err(e) => Err(IpcError::CkbSysError(e)),
         }
     }
@@ -1,19 +1,117 @@
-use serde::{Deserialize, Serialize};
-use crate::error::IpcError;
+//!
+//! Entry helpers for server scripts spawned by `spawn_server` or `SpawnBuilder`.
+//!
+use crate::channel::Channel;
+use crate::error::{IpcError, ProtocolErrorCode};
+use crate::ipc::{decode, Serve};
+use crate::pipe::Pipe;
+use ckb_std::{env::argv, error::SysError, high_level::inherited_fds};
+use serde::Deserialize;
 
-pub trait Serve {
-    /// Type of request.
-    type Req: Serialize;
+/// Returns the pipes to the client, which are the first two inherited fds.
+/// Additional fds passed by the client follow them and are left untouched.
+pub fn server_pipes() -> Result<(Pipe, Pipe), IpcError> {
+    let fds = inherited_fds();
+    if fds.len() < 2 {
+        return Err(IpcError::CkbSysError(SysError::InvalidFd));
+    }
+    let (reader, writer) = (Pipe::from(fds[0]), Pipe::from(fds[1]));
+    if !reader.readable() || !writer.writable() {
+        // not ours to close
+        reader.into_raw();
+        writer.into_raw();
+        return Err(IpcError::CkbSysError(SysError::InvalidFd));
+    }
+    Ok((reader, writer))
+}
+
+/// Decodes the configuration passed by `SpawnBuilder::config`, which is the
+/// first argv entry. Returns `IpcError::DeserializeError` if it's missing or
+/// malformed.
+pub fn server_config<T: for<'de> Deserialize<'de>>() -> Result<T, IpcError> {
+    let arg = argv().first().ok_or(IpcError::DeserializeError)?;
+    decode_config(arg.to_bytes())
+}
+
+/// Decodes a configuration encoded by `spawn::encode_config`.
+pub fn decode_config<T: for<'de> Deserialize<'de>>(arg: &[u8]) -> Result<T, IpcError> {
+    let bytes = hex::decode(arg).map_err(|_| IpcError::DeserializeError)?;
+    decode(&bytes)
+}
 
-    /// Type of response.
-    type Resp: Deserialize;
+/// Serves requests from the client until it closes the pipes. Returns the
+/// exit code of the server script: 0 on success, otherwise the protocol error
+/// code of the failure.
+///
+/// ```ignore
+/// pub fn program_entry() -> i8 {
+///     run_server(WorldServer.server())
+/// }
+/// ```
+pub fn run_server<S: Serve>(serve: S) -> i8 {
+    run_server_with(Channel::new, serve)
+}
 
-    /// Responds to a single request.
-    fn serve(self, req: Self::Req) -> Result<Self::Resp, IpcError>;
+/// Same as `run_server`, with the channel built by `channel` from the pipes to
+/// the client, e.g. to set its options, or the capacity of the buffer reading
+/// requests with `Channel::with_capacity`.
+///
+/// ```ignore
+/// pub fn program_entry() -> i8 {
+///     run_server_with(
+///         |reader, writer| Channel::new(reader, writer).with_max_payload_size(4096),
+///         WorldServer.server(),
+///     )
+/// }
+/// ```
+pub fn run_server_with<S, F>(channel: F, mut serve: S) -> i8
+where
+    S: Serve,
+    F: FnOnce(Pipe, Pipe) -> Channel,
+{
+    exit_code(
+        server_pipes().and_then(|(reader, writer)| channel(reader, writer).execute(&mut serve)),
+    )
+}
 
-    /// Extracts a method name from the request.
-    fn method(&self, _request: &Self::Req) -> Option<&' str> {
-        None
+fn exit_code(result: Result<(), IpcError>) -> i8 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            #[cfg(feature = "enable-logging")]
+            log::error!("Server exited with error: {:?}", e);
+            // codes beyond the range of exit codes, e.g. application defined
+            i8::try_from(ProtocolErrorCode::from(e).code())
+                .unwrap_or(ProtocolErrorCode::UnknownError.code() as i8)
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spawn::encode_config;
+    use serde::Serialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Config {
+        index: u64,
+        enabled: bool,
+    }
+
+    #[test]
+    fn test_config_round_trip() {
+        let config = Config {
+            index: 3,
+            enabled: true,
+        };
+        let arg = encode_config(&config).unwrap();
+        assert!(arg.to_bytes().iter().all(u8::is_ascii_hexdigit));
+        let decoded: Config = decode_config(arg.to_bytes()).unwrap();
+        assert_eq!(decoded, config);
+        assert!(matches!(
+            decode_config::<Config>(b"not hex"),
+            Err(IpcError::DeserializeError)
+        ));
+    }
+}
@@ -1,40 +1,228 @@
+use crate::channel::Channel;
 use crate::error::IpcError;
-use alloc::vec::Vec;
+use crate::ipc::encode;
+use crate::pipe::Pipe;
+use alloc::{ffi::CString, vec::Vec};
 use ckb_std::{
     ckb_constants::Source,
     ckb_types::core::ScriptHashType,
-    high_level::spawn_cell,
-    syscalls::{self, pipe},
+    error::SysError,
+    high_level::look_for_dep_with_hash2,
+    syscalls::{self, pipe, wait},
 };
 use core::ffi::CStr;
+use serde::Serialize;
 
-pub fn spawn_server(index: usize, source: Source, argv: &[&CStr]) -> Result<(u64, u64), IpcError> {
-    let (r1, w1) = pipe().map_err(IpcError::CkbSysError)?;
-    let (r2, w2) = pipe().map_err(IpcError::CkbSysError)?;
-    let inherited_fds = &[r2, w1];
+/// Handle of a spawned server. It owns the client ends of the pipes to the
+/// server, and can wait for the server to exit.
+pub struct ServerHandle {
+    process_id: u64,
+    pipes: Option<(Pipe, Pipe)>,
+}
+
+impl ServerHandle {
+    fn new(process_id: u64, reader: Pipe, writer: Pipe) -> Self {
+        Self {
+            process_id,
+            pipes: Some((reader, writer)),
+        }
+    }
+
+    pub fn process_id(&self) -> u64 {
+        self.process_id
+    }
+
+    /// Returns the pipes to read from and write to the server.
+    pub fn pipes_mut(&mut self) -> Result<(&mut Pipe, &mut Pipe), IpcError> {
+        match &mut self.pipes {
+            Some((reader, writer)) => Ok((reader, writer)),
+            None => Err(IpcError::CkbSysError(SysError::InvalidFd)),
+        }
+    }
+
+    /// Returns a channel to the server, over the pipes of this handle.
+    pub fn channel(&mut self) -> Result<Channel<&mut Pipe, &mut Pipe>, IpcError> {
+        let (reader, writer) = self.pipes_mut()?;
+        Ok(Channel::new(reader, writer))
+    }
+
+    /// Closes the pipes to the server. A server running `Channel::execute`
+    /// then leaves its loop.
+    pub fn close(&mut self) -> Result<(), IpcError> {
+        if let Some((reader, writer)) = self.pipes.take() {
+            let result = reader.close();
+            writer.close()?;
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Closes the pipes to the server, then waits for it to exit.
+    /// Returns the exit code of the server.
+    pub fn wait(mut self) -> Result<i8, IpcError> {
+        self.close()?;
+        wait(self.process_id).map_err(IpcError::CkbSysError)
+    }
+
+    /// Same as `wait`, but a non-zero exit code, e.g. from a crashed server,
+    /// is returned as `IpcError::ServerExit`.
+    pub fn join(self) -> Result<(), IpcError> {
+        match self.wait()? {
+            0 => Ok(()),
+            code => Err(IpcError::ServerExit(code)),
+        }
+    }
+}
+
+// where the server binary is located
+enum Target<'a> {
+    Cell(usize, Source),
+    CodeHash(&'a [u8], ScriptHashType),
+}
+
+/// Builder to spawn a server with full control over the `spawn` syscall
+/// parameters.
+///
+/// The first two inherited fds of the server are always the pipes to the
+/// client, in the order expected by `Channel`. Additional fds passed with
+/// `inherited_fd` follow them.
+///
+/// ```ignore
+/// let mut server = SpawnBuilder::new(0, Source::CellDep)
+///     .bounds(1024, 4096)
+///     .config(&config)?
+///     .spawn()?;
+/// let mut channel = server.channel()?;
+/// ```
+pub struct SpawnBuilder<'a> {
+    target: Target<'a>,
+    offset: u32,
+    length: u32,
+    config: Option<CString>,
+    argv: &'a [&'a CStr],
+    inherited_fds: Vec<u64>,
+}
+
+impl<'a> SpawnBuilder<'a> {
+    /// Spawns the server from the cell data at `index` of `source`.
+    pub fn new(index: usize, source: Source) -> Self {
+        Self::with_target(Target::Cell(index, source))
+    }
+
+    /// Spawns the server from the cell dep with `code_hash` and `hash_type`.
+    pub fn with_code_hash(code_hash: &'a [u8], hash_type: ScriptHashType) -> Self {
+        Self::with_target(Target::CodeHash(code_hash, hash_type))
+    }
 
-    let argc = argv.len();
-    let mut process_id: u64 = 0;
-    let argv_ptr: Vec<*const i8> = argv.iter().map(|&e| e.as_ptr()).collect();
-    let mut spgs = syscalls::SpawnArgs {
-        argc: argc as u64,
-        argv: argv_ptr.as_ptr(),
-        process_id: &mut process_id,
-        inherited_fds: inherited_fds.as_ptr(),
-    };
-    syscalls::spawn(index, source, 0, 0, &mut spgs).map_err(IpcError::CkbSysError)?;
-    Ok((r1, w2))
+    fn with_target(target: Target<'a>) -> Self {
+        Self {
+            target,
+            offset: 0,
+            length: 0,
+            config: None,
+            argv: &[],
+            inherited_fds: Vec::new(),
+        }
+    }
+
+    /// Loads the server from `length` bytes starting at `offset` of the cell
+    /// data. A `length` of 0 reads to the end of the data.
+    pub fn bounds(mut self, offset: u32, length: u32) -> Self {
+        self.offset = offset;
+        self.length = length;
+        self
+    }
+
+    /// Passes a configuration to the server as the first argv entry, before
+    /// the ones set by `argv`. The server reads it with
+    /// `server::server_config`.
+    pub fn config<T: Serialize>(mut self, config: &T) -> Result<Self, IpcError> {
+        self.config = Some(encode_config(config)?);
+        Ok(self)
+    }
+
+    pub fn argv(mut self, argv: &'a [&'a CStr]) -> Self {
+        self.argv = argv;
+        self
+    }
+
+    /// Passes an additional fd to the server, after the pipes of the channel.
+    pub fn inherited_fd(mut self, fd: u64) -> Self {
+        self.inherited_fds.push(fd);
+        self
+    }
+
+    /// Creates the pipes and spawns the server. The channel to the server is
+    /// available from `ServerHandle::channel`.
+    pub fn spawn(self) -> Result<ServerHandle, IpcError> {
+        let (index, source) = match self.target {
+            Target::Cell(index, source) => (index, source),
+            Target::CodeHash(code_hash, hash_type) => (
+                look_for_dep_with_hash2(code_hash, hash_type).map_err(IpcError::CkbSysError)?,
+                Source::CellDep,
+            ),
+        };
+        // owned by `Pipe`, so all of them are closed on any error below
+        let (r1, w1) = new_pipe()?;
+        let (r2, w2) = new_pipe()?;
+        // zero terminated
+        let mut inherited_fds = Vec::with_capacity(self.inherited_fds.len() + 3);
+        inherited_fds.extend_from_slice(&[r2.fd(), w1.fd()]);
+        inherited_fds.extend_from_slice(&self.inherited_fds);
+        inherited_fds.push(0);
+
+        let mut process_id: u64 = 0;
+        let argv_ptr: Vec<*const i8> = self
+            .config
+            .iter()
+            .map(|c| c.as_ptr())
+            .chain(self.argv.iter().map(|&e| e.as_ptr()))
+            .collect();
+        let mut spgs = syscalls::SpawnArgs {
+            argc: argv_ptr.len() as u64,
+            argv: argv_ptr.as_ptr(),
+            process_id: &mut process_id,
+            inherited_fds: inherited_fds.as_ptr(),
+        };
+        let bounds = ((self.offset as u64) << 32 | self.length as u64) as usize;
+        syscalls::spawn(index, source, PLACE_CELL_DATA, bounds, &mut spgs)
+            .map_err(IpcError::CkbSysError)?;
+        // the ends inherited by the server are no longer owned by this process
+        r2.into_raw();
+        w1.into_raw();
+        Ok(ServerHandle::new(process_id, r1, w2))
+    }
+}
+
+/// Encodes a configuration into an argv entry: the serialized bytes in hex, as
+/// argv entries can't contain NUL.
+pub fn encode_config<T: Serialize>(config: &T) -> Result<CString, IpcError> {
+    let hex = hex::encode(encode(config)?);
+    CString::new(hex).map_err(|_| IpcError::SerializeError)
+}
+
+fn new_pipe() -> Result<(Pipe, Pipe), IpcError> {
+    let (r, w) = pipe().map_err(IpcError::CkbSysError)?;
+    Ok((Pipe::new(r), Pipe::new(w)))
+}
+
+// `place` argument of `spawn`: load the binary from cell data
+const PLACE_CELL_DATA: usize = 0;
+
+pub fn spawn_server(
+    index: usize,
+    source: Source,
+    argv: &[&CStr],
+) -> Result<ServerHandle, IpcError> {
+    SpawnBuilder::new(index, source).argv(argv).spawn()
 }
 
 pub fn spawn_cell_server(
     code_hash: &[u8],
     hash_type: ScriptHashType,
     argv: &[&CStr],
-) -> Result<(u64, u64), IpcError> {
-    let (r1, w1) = pipe().map_err(IpcError::CkbSysError)?;
-    let (r2, w2) = pipe().map_err(IpcError::CkbSysError)?;
-    let inherited_fds = &[r2, w1];
-
-    spawn_cell(code_hash, hash_type, argv, inherited_fds).map_err(IpcError::CkbSysError)?;
-    Ok((r1, w2))
+) -> Result<ServerHandle, IpcError> {
+    SpawnBuilder::with_code_hash(code_hash, hash_type)
+        .argv(argv)
+        .spawn()
 }
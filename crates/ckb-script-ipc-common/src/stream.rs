@@ -0,0 +1,400 @@
+//!
+//! Streaming responses, for results too large or too slow to be returned at
+//! once.
+//!
+//! The client pulls items by granting credit to the server, which sends at
+//! most that many items before waiting for more. The stream ends with the
+//! normal response to the request. Cancelling a stream is granting no more
+//! credit: the server stops at its next wait, and the client only has to skip
+//! the items already granted.
+//!
+//! ```ignore
+//! // server, in `Serve::serve_with_peer`
+//! peer.send_stream(cells.iter())?;
+//! Ok(CellsResponse::Done)
+//!
+//! // client
+//! let mut stream = channel.call_stream::<_, Cell, CellsResponse>("Cells.all", req)?;
+//! for cell in &mut stream {
+//!     let cell = cell?;
+//! }
+//! let resp = stream.finish()?;
+//! ```
+//!
+use crate::channel::{check_response, Channel, Peer};
+use crate::duplex::Then;
+use crate::error::IpcError;
+use crate::io::{Read, Write};
+use crate::ipc::{decode, encode, Message};
+use crate::packet::{Frame, Packet, ResponsePacket, StreamCreditPacket, StreamItemPacket};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use serde::{Deserialize, Serialize};
+
+/// Default number of items the client grants at once.
+pub const DEFAULT_STREAM_WINDOW: u64 = 16;
+
+/// Client end of a streaming response, iterating over its items. Items are
+/// deserialized lazily, as they are iterated.
+///
+/// Dropping it before the end cancels the stream, ignoring errors. Use
+/// `cancel` or `finish` to get them.
+pub struct ResponseStream<'a, R, W, T, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    channel: &'a mut Channel<R, W>,
+    request_id: u64,
+    method_id: u64,
+    window: u64,
+    // number of items the server can still send
+    credit: u64,
+    cancelled: bool,
+    // the response ending the stream, once received
+    end: Option<ResponsePacket>,
+    // nothing more to receive: the stream has ended, or the channel is out of
+    // sync
+    done: bool,
+    _marker: PhantomData<(T, Resp)>,
+}
+
+impl<R, W> Channel<R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    /// Sends a request answered with a streaming response, see `Peer::send_stream`.
+    pub fn call_stream<Req, T, Resp>(
+        &mut self,
+        method_name: &'static str,
+        req: Req,
+    ) -> Result<ResponseStream<'_, R, W, T, Resp>, IpcError>
+    where
+        Req: Message,
+        T: for<'de> Deserialize<'de>,
+        Resp: Message,
+    {
+        self.call_stream_with_window(method_name, req, DEFAULT_STREAM_WINDOW)
+    }
+
+    /// Same as `call_stream`, granting `window` items at once. A larger
+    /// window saves round trips, at the cost of more items to skip when
+    /// the stream is cancelled.
+    pub fn call_stream_with_window<Req, T, Resp>(
+        &mut self,
+        _method_name: &'static str,
+        req: Req,
+        window: u64,
+    ) -> Result<ResponseStream<'_, R, W, T, Resp>, IpcError>
+    where
+        Req: Message,
+        T: for<'de> Deserialize<'de>,
+        Resp: Message,
+    {
+        let window = window.max(1);
+        let packet = self.request_packet(&req)?;
+        #[cfg(feature = "enable-logging")]
+        log::info!("send stream request({}): {:?}", _method_name, packet);
+        let (request_id, method_id) = (packet.request_id(), packet.method_id());
+        // the request and the first credit are sent with a single write
+        let mut bytes = packet.serialize();
+        bytes.extend(StreamCreditPacket::new(request_id, window).serialize());
+        self.write_bytes(&bytes, Then::Read)?;
+        Ok(ResponseStream {
+            channel: self,
+            request_id,
+            method_id,
+            window,
+            credit: window,
+            cancelled: false,
+            end: None,
+            done: false,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<'a, R, W, T, Resp> ResponseStream<'a, R, W, T, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+    T: for<'de> Deserialize<'de>,
+    Resp: Message,
+{
+    /// Cancels the stream, skipping the items in flight until its end.
+    pub fn cancel(&mut self) -> Result<(), IpcError> {
+        if self.done {
+            return Ok(());
+        }
+        if !self.cancelled {
+            self.cancelled = true;
+            let packet = StreamCreditPacket::new(self.request_id, 0);
+            self.channel
+                .write_packet(&packet, Then::Read)
+                .map_err(|e| self.fail(e))?;
+        }
+        while !self.done {
+            self.receive()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the response ending the stream. The stream is cancelled if
+    /// it hasn't ended yet.
+    pub fn finish(mut self) -> Result<Resp, IpcError> {
+        self.cancel()?;
+        match self.end.take() {
+            Some(packet) => check_response(packet, self.request_id, self.method_id),
+            None => Err(IpcError::UnexpectedResponse),
+        }
+    }
+
+    // receives the next packet of the stream, returns the payload of an item
+    fn receive(&mut self) -> Result<Option<StreamItemPacket>, IpcError> {
+        let frame = self.channel.next_frame(0).map_err(|e| self.fail(e))?;
+        match frame {
+            Frame::StreamItem(packet) if packet.request_id() == self.request_id => {
+                self.credit = self.credit.saturating_sub(1);
+                Ok(Some(packet))
+            }
+            Frame::Response(packet) => {
+                self.end = Some(packet);
+                self.done = true;
+                Ok(None)
+            }
+            frame => Err(self.fail(IpcError::UnexpectedPacket(frame.kind()))),
+        }
+    }
+
+    fn fail(&mut self, e: IpcError) -> IpcError {
+        self.done = true;
+        e
+    }
+}
+
+impl<'a, R, W, T, Resp> Iterator for ResponseStream<'a, R, W, T, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+    T: for<'de> Deserialize<'de>,
+    Resp: Message,
+{
+    type Item = Result<T, IpcError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.cancelled {
+            return None;
+        }
+        if self.credit == 0 {
+            let packet = StreamCreditPacket::new(self.request_id, self.window);
+            if let Err(e) = self.channel.write_packet(&packet, Then::Read) {
+                return Some(Err(self.fail(e)));
+            }
+            self.credit = self.window;
+        }
+        match self.receive() {
+            Ok(Some(packet)) => Some(decode(packet.payload())),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl<'a, R, W, T, Resp> Drop for ResponseStream<'a, R, W, T, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut bytes = Vec::new();
+        if !self.cancelled {
+            bytes = StreamCreditPacket::new(self.request_id, 0).serialize();
+        }
+        self.channel.abandon_call(&bytes);
+    }
+}
+
+impl<'a, R, W> Peer<'a, R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    /// Sends `items` as a streaming response to the request being served, as
+    /// fast as the client grants credit. The stream ends with the response
+    /// returned by the handler afterwards.
+    ///
+    /// Returns `false` if the client cancels the stream before all items are
+    /// sent. A notification can't be answered with a stream, which is
+    /// cancelled right away. Over a loopback transport, all the items are sent
+    /// at once, as the client can't grant credit while the request is served.
+    pub fn send_stream<T, I>(&mut self, items: I) -> Result<bool, IpcError>
+    where
+        T: Serialize,
+        I: IntoIterator<Item = T>,
+    {
+        if self.request_id == 0 {
+            return Ok(false);
+        }
+        if self.channel.loopback {
+            for item in items {
+                let packet = StreamItemPacket::new(self.request_id, encode(&item)?);
+                self.channel.write_packet(&packet, Then::Write)?;
+            }
+            return Ok(true);
+        }
+        let mut items = items.into_iter().peekable();
+        while items.peek().is_some() {
+            let credit = self.receive_credit()?;
+            if credit == 0 {
+                #[cfg(feature = "enable-logging")]
+                log::info!("stream cancelled, request_id: {}", self.request_id);
+                return Ok(false);
+            }
+            for item in items.by_ref().take(credit.min(usize::MAX as u64) as usize) {
+                let packet = StreamItemPacket::new(self.request_id, encode(&item)?);
+                self.channel.write_packet(&packet, Then::Write)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn receive_credit(&mut self) -> Result<u64, IpcError> {
+        match self.channel.next_frame(self.request_id)? {
+            Frame::StreamCredit(packet) => Ok(packet.credit()),
+            frame => Err(IpcError::UnexpectedPacket(frame.kind())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProtocolErrorCode;
+    use crate::test_utils::{
+        chunks, request, response, spawn, unchunk, AddRequest, AddResponse, Range, Spawned, ADD,
+    };
+
+    fn item(request_id: u64, value: u64) -> Vec<u8> {
+        StreamItemPacket::new(request_id, encode(&value).unwrap()).serialize()
+    }
+
+    fn credit(request_id: u64, credit: u64) -> Vec<u8> {
+        StreamCreditPacket::new(request_id, credit).serialize()
+    }
+
+    #[test]
+    fn test_send_stream() {
+        let incoming = chunks(&[[request(1, 0, 3), credit(1, 2)].concat(), credit(1, 2)]);
+        let mut outgoing = Vec::new();
+        Channel::new(&incoming[..], &mut outgoing)
+            .execute(&mut Range)
+            .unwrap();
+        let expected = [item(1, 0), item(1, 1), item(1, 2), response(1, 1)].concat();
+        assert_eq!(unchunk(&outgoing), expected);
+
+        // cancelled after the first credit
+        let incoming = chunks(&[[request(1, 0, 3), credit(1, 2)].concat(), credit(1, 0)]);
+        let mut outgoing = Vec::new();
+        Channel::new(&incoming[..], &mut outgoing)
+            .execute(&mut Range)
+            .unwrap();
+        assert_eq!(
+            unchunk(&outgoing),
+            [item(1, 0), item(1, 1), response(1, 0)].concat()
+        );
+    }
+
+    #[test]
+    fn test_call_stream() {
+        let incoming = chunks(&[item(1, 0), item(1, 1), item(1, 2), response(1, 1)]);
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing);
+        let mut stream = client
+            .call_stream_with_window::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 3 }, 2)
+            .unwrap();
+        let items: Result<Vec<u64>, IpcError> = (&mut stream).collect();
+        assert_eq!(items.unwrap(), [0, 1, 2]);
+        assert_eq!(stream.finish().unwrap().0, 1);
+        // more credit is granted once the first is used up
+        assert_eq!(
+            unchunk(&outgoing),
+            [request(1, 0, 3), credit(1, 2), credit(1, 2)].concat()
+        );
+    }
+
+    #[test]
+    fn test_cancel_stream() {
+        let incoming = chunks(&[item(1, 0), item(1, 1), response(1, 0)]);
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing);
+        let mut stream = client
+            .call_stream_with_window::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 3 }, 2)
+            .unwrap();
+        assert_eq!(stream.next().unwrap().unwrap(), 0);
+        // the item in flight is skipped
+        assert_eq!(stream.finish().unwrap().0, 0);
+        assert_eq!(
+            unchunk(&outgoing),
+            [request(1, 0, 3), credit(1, 2), credit(1, 0)].concat()
+        );
+    }
+
+    #[test]
+    fn test_drop_stream() {
+        // a stream dropped before its end, then a call
+        let incoming = chunks(&[item(1, 0), response(1, 0), response(2, 3)]);
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing);
+        let stream = client
+            .call_stream::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 3 })
+            .unwrap();
+        drop(stream);
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 1, b: 2 }).unwrap();
+        assert_eq!(resp.0, 3);
+    }
+
+    #[test]
+    fn test_stream_error() {
+        let code = ProtocolErrorCode::InvalidData.code();
+        let incoming = chunks(&[ResponsePacket::new(1, ADD, code, Vec::new()).serialize()]);
+        let mut client = Channel::new(&incoming[..], Vec::new());
+        let mut stream = client
+            .call_stream::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 3 })
+            .unwrap();
+        assert!(stream.next().is_none());
+        assert!(matches!(
+            stream.finish(),
+            Err(IpcError::ProtocolError(ProtocolErrorCode::InvalidData, _))
+        ));
+    }
+
+    #[test]
+    fn test_stream_over_pipes() {
+        let Spawned {
+            mut client, server, ..
+        } = spawn(Range, true);
+        let mut stream = client
+            .call_stream_with_window::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 40 }, 4)
+            .unwrap();
+        let items: Result<Vec<u64>, IpcError> = (&mut stream).collect();
+        assert_eq!(items.unwrap(), (0..40).collect::<Vec<u64>>());
+        assert_eq!(stream.finish().unwrap().0, 1);
+        // a stream dropped while items are in flight, then another one
+        let mut stream = client
+            .call_stream_with_window::<_, u64, AddResponse>("Range", AddRequest { a: 0, b: 40 }, 4)
+            .unwrap();
+        assert_eq!(stream.next().unwrap().unwrap(), 0);
+        drop(stream);
+        let stream = client
+            .call_stream::<_, u64, AddResponse>("Range", AddRequest { a: 5, b: 8 })
+            .unwrap();
+        let items: Result<Vec<u64>, IpcError> = stream.collect();
+        assert_eq!(items.unwrap(), [5, 6, 7]);
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,478 @@
+//! Simple services, and in-memory pipes, used in unit tests.
+extern crate std;
+
+use crate::channel::{Channel, Peer};
+use crate::duplex::{read_chunk, serialize_chunk, PASS};
+use crate::error::{ErrorDetail, IpcError, ProtocolErrorCode};
+use crate::io::{Read, Write};
+use crate::ipc::{decode, encode, Message, Serve};
+use crate::packet::{Packet, RequestPacket, ResponsePacket};
+use alloc::{boxed::Box, rc::Rc, sync::Arc, vec::Vec};
+use ckb_std::error::SysError;
+use core::cell::Cell;
+use core::cmp;
+use serde::{Deserialize, Serialize};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+pub const ADD: u64 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct AddRequest {
+    pub a: u64,
+    pub b: u64,
+}
+
+impl Message for AddRequest {
+    fn method_id(&self) -> u64 {
+        ADD
+    }
+    fn encode(&self) -> Result<Vec<u8>, IpcError> {
+        encode(self)
+    }
+    fn decode(method_id: u64, payload: &[u8]) -> Result<Self, IpcError> {
+        match method_id {
+            ADD => decode(payload),
+            _ => Err(IpcError::UnknownMethod(method_id)),
+        }
+    }
+}
+
+pub struct AddResponse(pub u64);
+
+impl Message for AddResponse {
+    fn method_id(&self) -> u64 {
+        ADD
+    }
+    fn encode(&self) -> Result<Vec<u8>, IpcError> {
+        encode(&self.0)
+    }
+    fn decode(method_id: u64, payload: &[u8]) -> Result<Self, IpcError> {
+        match method_id {
+            ADD => Ok(AddResponse(decode(payload)?)),
+            _ => Err(IpcError::UnknownMethod(method_id)),
+        }
+    }
+}
+
+pub struct Adder;
+
+impl Serve for Adder {
+    type Req = AddRequest;
+    type Resp = AddResponse;
+    fn serve(&mut self, req: AddRequest) -> Result<AddResponse, IpcError> {
+        match req.a.checked_add(req.b) {
+            Some(sum) => Ok(AddResponse(sum)),
+            None => {
+                let detail = ErrorDetail::new("overflow").with_app_error(&req.a)?;
+                Err(IpcError::ProtocolError(
+                    ProtocolErrorCode::InvalidData,
+                    Some(Box::new(detail)),
+                ))
+            }
+        }
+    }
+    fn method(&self, _request: &AddRequest) -> Option<&'static str> {
+        Some("Adder.add")
+    }
+}
+
+/// Adds `a + b` to a total shared with the test, responds with the total.
+pub struct Totaling(pub Rc<Cell<u64>>);
+
+impl Serve for Totaling {
+    type Req = AddRequest;
+    type Resp = AddResponse;
+    fn serve(&mut self, req: AddRequest) -> Result<AddResponse, IpcError> {
+        self.0.set(self.0.get() + req.a + req.b);
+        Ok(AddResponse(self.0.get()))
+    }
+}
+
+pub struct FillResponse(pub Vec<u8>);
+
+impl Message for FillResponse {
+    fn method_id(&self) -> u64 {
+        ADD
+    }
+    fn encode(&self) -> Result<Vec<u8>, IpcError> {
+        encode(&self.0)
+    }
+    fn decode(method_id: u64, payload: &[u8]) -> Result<Self, IpcError> {
+        match method_id {
+            ADD => Ok(FillResponse(decode(payload)?)),
+            _ => Err(IpcError::UnknownMethod(method_id)),
+        }
+    }
+}
+
+/// Responds with `a` bytes of value `b`.
+pub struct Filling;
+
+impl Serve for Filling {
+    type Req = AddRequest;
+    type Resp = FillResponse;
+    fn serve(&mut self, req: AddRequest) -> Result<FillResponse, IpcError> {
+        Ok(FillResponse(alloc::vec![req.b as u8; req.a as usize]))
+    }
+}
+
+/// Streams `a..b`, responds with 1 if all the items are sent.
+pub struct Range;
+
+impl Serve for Range {
+    type Req = AddRequest;
+    type Resp = AddResponse;
+    fn serve(&mut self, _req: AddRequest) -> Result<AddResponse, IpcError> {
+        unreachable!()
+    }
+    fn serve_with_peer<R, W>(
+        &mut self,
+        req: AddRequest,
+        peer: &mut Peer<'_, R, W>,
+    ) -> Result<AddResponse, IpcError>
+    where
+        R: Read<Error = IpcError>,
+        W: Write<Error = IpcError>,
+    {
+        let completed = peer.send_stream(req.a..req.b)?;
+        Ok(AddResponse(completed as u64))
+    }
+}
+
+/// Serves `a + b`, calling back the client to compute `a + a` first.
+pub struct CallingBack;
+
+impl Serve for CallingBack {
+    type Req = AddRequest;
+    type Resp = AddResponse;
+    fn serve(&mut self, _req: AddRequest) -> Result<AddResponse, IpcError> {
+        unreachable!()
+    }
+    fn serve_with_peer<R, W>(
+        &mut self,
+        req: AddRequest,
+        peer: &mut Peer<'_, R, W>,
+    ) -> Result<AddResponse, IpcError>
+    where
+        R: Read<Error = IpcError>,
+        W: Write<Error = IpcError>,
+    {
+        let double: AddResponse = peer.call("Adder.add", AddRequest { a: req.a, b: req.a })?;
+        Ok(AddResponse(double.0 + req.b))
+    }
+}
+
+/// Notifies the client of `a + b`, then responds with it.
+pub struct Notifying;
+
+impl Serve for Notifying {
+    type Req = AddRequest;
+    type Resp = AddResponse;
+    fn serve(&mut self, _req: AddRequest) -> Result<AddResponse, IpcError> {
+        unreachable!()
+    }
+    fn serve_with_peer<R, W>(
+        &mut self,
+        req: AddRequest,
+        peer: &mut Peer<'_, R, W>,
+    ) -> Result<AddResponse, IpcError>
+    where
+        R: Read<Error = IpcError>,
+        W: Write<Error = IpcError>,
+    {
+        peer.send_notification(AddRequest { a: req.a, b: req.b })?;
+        Ok(AddResponse(req.a + req.b))
+    }
+}
+
+/// Reads up to `a` bytes of the upload, responds with their sum.
+pub struct Summing;
+
+impl Serve for Summing {
+    type Req = AddRequest;
+    type Resp = AddResponse;
+    fn serve(&mut self, _req: AddRequest) -> Result<AddResponse, IpcError> {
+        unreachable!()
+    }
+    fn serve_with_peer<R, W>(
+        &mut self,
+        req: AddRequest,
+        peer: &mut Peer<'_, R, W>,
+    ) -> Result<AddResponse, IpcError>
+    where
+        R: Read<Error = IpcError>,
+        W: Write<Error = IpcError>,
+    {
+        let mut source = peer.upload();
+        let (mut sum, mut left) = (0, req.a as usize);
+        let mut buf = [0u8; 2];
+        while left > 0 {
+            let len = cmp::min(left, buf.len());
+            let n = source.read(&mut buf[..len])?;
+            if n == 0 {
+                break;
+            }
+            sum += buf[..n].iter().map(|b| *b as u64).sum::<u64>();
+            left -= n;
+        }
+        Ok(AddResponse(sum))
+    }
+}
+
+/// Serializes a request to `Adder`.
+pub fn request(request_id: u64, a: u64, b: u64) -> Vec<u8> {
+    let payload = AddRequest { a, b }.encode().unwrap();
+    RequestPacket::new(request_id, ADD, payload).serialize()
+}
+
+/// Serializes a response of `Adder`, or of services reusing its messages.
+pub fn response(request_id: u64, value: u64) -> Vec<u8> {
+    let payload = AddResponse(value).encode().unwrap();
+    ResponsePacket::new(request_id, ADD, 0, payload).serialize()
+}
+
+/// Serializes each frame as a chunk passing the turn, as sent by an end
+/// waiting for a reply after each of them.
+pub fn chunks(frames: &[Vec<u8>]) -> Vec<u8> {
+    frames
+        .iter()
+        .flat_map(|frame| serialize_chunk(frame, PASS))
+        .collect()
+}
+
+/// Serializes `n` empty chunks passing the turn, as sent by an end waiting for
+/// more data, so that the other end can keep writing.
+pub fn turns(n: usize) -> Vec<u8> {
+    (0..n).flat_map(|_| serialize_chunk(&[], PASS)).collect()
+}
+
+/// Concatenates the data of the chunks.
+pub fn unchunk(mut bytes: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    while let Some((chunk, _)) = read_chunk(&mut bytes, usize::MAX).unwrap() {
+        data.extend(chunk);
+    }
+    data
+}
+
+/// Counts the chunks passing the turn.
+pub fn passes(mut bytes: &[u8]) -> usize {
+    let mut passes = 0;
+    while let Some((_, flags)) = read_chunk(&mut bytes, usize::MAX).unwrap() {
+        passes += (flags & PASS != 0) as usize;
+    }
+    passes
+}
+
+/// One direction of a pipe pair, written by one end and read by the other.
+#[derive(Default)]
+struct Slot {
+    data: Vec<u8>,
+    pos: usize,
+    writer_closed: bool,
+    reader_closed: bool,
+}
+
+#[derive(Default)]
+struct State {
+    // `slots[i]` is written by end `i`
+    slots: [Slot; 2],
+    // whether end `i` is blocked, until the other end makes progress for it
+    waiting: [bool; 2],
+    deadlock: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+impl Shared {
+    // Blocks `end` until woken. Fails if the other end is blocked too, as it
+    // can't make progress for this one any more.
+    fn wait<'a>(
+        &self,
+        mut state: MutexGuard<'a, State>,
+        end: usize,
+    ) -> Result<MutexGuard<'a, State>, IpcError> {
+        if !state.deadlock && state.waiting[1 - end] {
+            state.deadlock = true;
+            self.cond.notify_all();
+        }
+        if state.deadlock {
+            return Err(IpcError::Deadlock);
+        }
+        state.waiting[end] = true;
+        let state = self.cond.wait(state).unwrap();
+        if state.deadlock {
+            return Err(IpcError::Deadlock);
+        }
+        Ok(state)
+    }
+
+    // Wakes the other end of `end`, which may be able to make progress.
+    fn wake(&self, state: &mut State, end: usize) {
+        state.waiting[1 - end] = false;
+        self.cond.notify_all();
+    }
+}
+
+/// Reading end of an in-memory pipe, see `pipe_pair`.
+pub struct PipeReader {
+    shared: Arc<Shared>,
+    end: usize,
+}
+
+/// Writing end of an in-memory pipe, see `pipe_pair`.
+pub struct PipeWriter {
+    shared: Arc<Shared>,
+    end: usize,
+}
+
+/// Creates the two ends of a connection over in-memory pipes which, like pipes
+/// between scripts, block writes until the other end reads all the data. An
+/// end which would block while the other end is blocked too fails with
+/// `IpcError::Deadlock`, as does any blocking call on the pair afterwards.
+pub fn pipe_pair() -> ((PipeReader, PipeWriter), (PipeReader, PipeWriter)) {
+    let shared = Arc::new(Shared::default());
+    let end = |end| {
+        (
+            PipeReader {
+                shared: shared.clone(),
+                end,
+            },
+            PipeWriter {
+                shared: shared.clone(),
+                end,
+            },
+        )
+    };
+    (end(0), end(1))
+}
+
+impl Read for PipeReader {
+    type Error = IpcError;
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IpcError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            let slot = &mut state.slots[1 - self.end];
+            if slot.pos < slot.data.len() {
+                let n = cmp::min(buf.len(), slot.data.len() - slot.pos);
+                buf[..n].copy_from_slice(&slot.data[slot.pos..slot.pos + n]);
+                slot.pos += n;
+                shared.wake(&mut state, self.end);
+                return Ok(n);
+            }
+            if slot.writer_closed {
+                return Ok(0);
+            }
+            state = shared.wait(state, self.end)?;
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.slots[1 - self.end].reader_closed = true;
+        self.shared.wake(&mut state, self.end);
+    }
+}
+
+impl Write for PipeWriter {
+    type Error = IpcError;
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IpcError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let slot = &mut state.slots[self.end];
+        slot.data = buf.to_vec();
+        slot.pos = 0;
+        shared.wake(&mut state, self.end);
+        loop {
+            let slot = &state.slots[self.end];
+            if slot.pos == slot.data.len() {
+                return Ok(buf.len());
+            }
+            if slot.reader_closed {
+                return Err(IpcError::CkbSysError(SysError::OtherEndClosed));
+            }
+            state = shared.wait(state, self.end)?;
+        }
+    }
+    fn flush(&mut self) -> Result<(), IpcError> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.slots[self.end].writer_closed = true;
+        self.shared.wake(&mut state, self.end);
+    }
+}
+
+/// Writing end of a pipe, recording the bytes written.
+pub struct Recorder {
+    writer: PipeWriter,
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Write for Recorder {
+    type Error = IpcError;
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IpcError> {
+        let n = self.writer.write(buf)?;
+        self.bytes.lock().unwrap().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> Result<(), IpcError> {
+        self.writer.flush()
+    }
+}
+
+/// A server running in another thread, and its client, see `spawn`.
+pub struct Spawned {
+    pub client: Channel<PipeReader, Recorder>,
+    pub server: JoinHandle<Result<(), IpcError>>,
+    pub client_bytes: Arc<Mutex<Vec<u8>>>,
+    pub server_bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+/// Runs `serve` in another thread, over pipes blocking writes until read.
+/// Buffers and chunks are small, so that frames are sent and read in pieces.
+pub fn spawn<S: Serve + Send + 'static>(serve: S, flow_control: bool) -> Spawned {
+    let ((client_reader, client_writer), (server_reader, server_writer)) = pipe_pair();
+    let client_bytes = Arc::new(Mutex::new(Vec::new()));
+    let server_bytes = Arc::new(Mutex::new(Vec::new()));
+    let client_writer = Recorder {
+        writer: client_writer,
+        bytes: client_bytes.clone(),
+    };
+    let server_writer = Recorder {
+        writer: server_writer,
+        bytes: server_bytes.clone(),
+    };
+    let mut server = Channel::with_capacity(16, server_reader, server_writer).with_chunk_size(16);
+    let mut client = Channel::with_capacity(16, client_reader, client_writer).with_chunk_size(16);
+    if !flow_control {
+        server = server.without_flow_control();
+        client = client.without_flow_control();
+    }
+    let mut serve = serve;
+    let server = thread::spawn(move || server.execute(&mut serve));
+    Spawned {
+        client,
+        server,
+        client_bytes,
+        server_bytes,
+    }
+}
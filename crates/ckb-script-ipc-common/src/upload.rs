@@ -0,0 +1,422 @@
+//!
+//! Chunked uploads, for request arguments too large to be sent in a single
+//! payload.
+//!
+//! The client sends the request, then writes the data through an
+//! `io::Write` sink, which sends it in chunks. The handler reads it through
+//! an `io::Read` source. Over pipes, the server buffers at most a window of
+//! chunks while it waits for the turn, see `duplex`. Over a loopback
+//! transport, the whole upload is buffered until the client finishes it, see
+//! `loopback`.
+//!
+//! ```ignore
+//! // server, in `Serve::serve_with_peer`
+//! let mut source = peer.upload();
+//! let mut buf = [0u8; 256];
+//! loop {
+//!     let n = source.read(&mut buf)?;
+//!     if n == 0 {
+//!         break;
+//!     }
+//!     hasher.update(&buf[..n]);
+//! }
+//! Ok(HashResponse::Hash(hasher.finalize()))
+//!
+//! // client
+//! let mut sink = channel.call_upload::<_, HashResponse>("Hasher.hash", req)?;
+//! sink.write_all(&witness)?;
+//! let resp = sink.finish()?;
+//! ```
+//!
+use crate::channel::{Channel, Peer};
+use crate::duplex::Then;
+use crate::error::IpcError;
+use crate::io::{Read, Write};
+use crate::ipc::Message;
+use crate::packet::{Frame, Packet, StreamItemPacket};
+use alloc::vec::Vec;
+use core::cmp;
+use core::marker::PhantomData;
+
+/// Default size of the chunks of an upload.
+pub const DEFAULT_UPLOAD_CHUNK_SIZE: usize = 4096;
+
+/// Client end of an upload, sending the data written to it in chunks.
+///
+/// The upload ends with `finish`, which returns the response. Dropping it
+/// before ends the upload with the data written so far, ignoring the
+/// response.
+pub struct UploadSink<'a, R, W, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    channel: &'a mut Channel<R, W>,
+    request_id: u64,
+    method_id: u64,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    // the end of the upload is sent, or the channel is out of sync
+    done: bool,
+    _marker: PhantomData<Resp>,
+}
+
+impl<R, W> Channel<R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    /// Sends a request followed by an upload, see `Peer::upload`.
+    pub fn call_upload<Req, Resp>(
+        &mut self,
+        method_name: &'static str,
+        req: Req,
+    ) -> Result<UploadSink<'_, R, W, Resp>, IpcError>
+    where
+        Req: Message,
+        Resp: Message,
+    {
+        self.call_upload_with_chunk_size(method_name, req, DEFAULT_UPLOAD_CHUNK_SIZE)
+    }
+
+    /// Same as `call_upload`, with chunks of `chunk_size` bytes. They must
+    /// not exceed the maximum payload size of the server.
+    pub fn call_upload_with_chunk_size<Req, Resp>(
+        &mut self,
+        _method_name: &'static str,
+        req: Req,
+        chunk_size: usize,
+    ) -> Result<UploadSink<'_, R, W, Resp>, IpcError>
+    where
+        Req: Message,
+        Resp: Message,
+    {
+        let packet = self.request_packet(&req)?;
+        #[cfg(feature = "enable-logging")]
+        log::info!("send upload request({}): {:?}", _method_name, packet);
+        self.write_packet(&packet, Then::Write)?;
+        let chunk_size = chunk_size.max(1);
+        Ok(UploadSink {
+            channel: self,
+            request_id: packet.request_id(),
+            method_id: packet.method_id(),
+            chunk_size,
+            buf: Vec::with_capacity(chunk_size),
+            done: false,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<'a, R, W, Resp> UploadSink<'a, R, W, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+    Resp: Message,
+{
+    /// Ends the upload, and receives the response.
+    pub fn finish(mut self) -> Result<Resp, IpcError> {
+        self.end()?;
+        self.channel
+            .receive_response(self.request_id, self.method_id)
+    }
+
+    fn end(&mut self) -> Result<(), IpcError> {
+        self.send_chunk()?;
+        self.done = true;
+        // the end of the upload is an empty chunk
+        self.channel.write_packet(
+            &StreamItemPacket::new(self.request_id, Vec::new()),
+            Then::Read,
+        )
+    }
+}
+
+impl<'a, R, W, Resp> UploadSink<'a, R, W, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    fn send_chunk(&mut self) -> Result<(), IpcError> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = core::mem::replace(&mut self.buf, Vec::with_capacity(self.chunk_size));
+        self.channel
+            .write_packet(&StreamItemPacket::new(self.request_id, chunk), Then::Write)
+            .map_err(|e| {
+                self.done = true;
+                e
+            })
+    }
+}
+
+impl<'a, R, W, Resp> Write for UploadSink<'a, R, W, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    type Error = IpcError;
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IpcError> {
+        if self.done {
+            return Err(IpcError::EndOfStream);
+        }
+        let n = cmp::min(buf.len(), self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == self.chunk_size {
+            self.send_chunk()?;
+        }
+        Ok(n)
+    }
+
+    /// Sends the data written so far, even if it doesn't fill a chunk.
+    fn flush(&mut self) -> Result<(), IpcError> {
+        if self.done {
+            return Err(IpcError::EndOfStream);
+        }
+        self.send_chunk()
+    }
+}
+
+impl<'a, R, W, Resp> Drop for UploadSink<'a, R, W, Resp>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        self.done = true;
+        let chunk = core::mem::take(&mut self.buf);
+        let mut bytes = Vec::new();
+        if !chunk.is_empty() {
+            bytes.extend(StreamItemPacket::new(self.request_id, chunk).serialize());
+        }
+        bytes.extend(StreamItemPacket::new(self.request_id, Vec::new()).serialize());
+        self.channel.abandon_call(&bytes);
+    }
+}
+
+/// Server end of an upload, reading the chunks sent by the client.
+///
+/// Dropping it before the end skips the chunks left.
+pub struct UploadSource<'b, R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    channel: &'b mut Channel<R, W>,
+    request_id: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+    // the end of the upload is received, or the channel is out of sync
+    done: bool,
+}
+
+impl<'a, R, W> Peer<'a, R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    /// Returns the data uploaded by the client after the request being
+    /// served. A notification has no upload, its source is empty.
+    pub fn upload(&mut self) -> UploadSource<'_, R, W> {
+        UploadSource {
+            request_id: self.request_id,
+            chunk: Vec::new(),
+            pos: 0,
+            done: self.request_id == 0,
+            channel: &mut *self.channel,
+        }
+    }
+}
+
+impl<'b, R, W> UploadSource<'b, R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    fn receive(&mut self) -> Result<(), IpcError> {
+        let result = match self.channel.next_frame(0) {
+            Ok(Frame::StreamItem(packet)) if packet.request_id() == self.request_id => {
+                Ok(packet.into_payload())
+            }
+            Ok(frame) => Err(IpcError::UnexpectedPacket(frame.kind())),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(chunk) => {
+                self.done = chunk.is_empty();
+                self.chunk = chunk;
+                self.pos = 0;
+                Ok(())
+            }
+            Err(e) => {
+                self.done = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<'b, R, W> Read for UploadSource<'b, R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    type Error = IpcError;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IpcError> {
+        while self.pos == self.chunk.len() {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+            self.receive()?;
+        }
+        let n = cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<'b, R, W> Drop for UploadSource<'b, R, W>
+where
+    R: Read<Error = IpcError>,
+    W: Write<Error = IpcError>,
+{
+    fn drop(&mut self) {
+        // keep the channel in sync for the response and later requests
+        while !self.done {
+            if self.receive().is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        chunks, request, response, spawn, unchunk, AddRequest, AddResponse, Spawned, Summing,
+    };
+
+    fn chunk(request_id: u64, data: &[u8]) -> Vec<u8> {
+        StreamItemPacket::new(request_id, data.to_vec()).serialize()
+    }
+
+    #[test]
+    fn test_upload() {
+        let incoming = chunks(&[
+            request(1, 100, 0),
+            chunk(1, &[1, 2, 3]),
+            chunk(1, &[4]),
+            chunk(1, &[]),
+        ]);
+        let mut outgoing = Vec::new();
+        Channel::new(&incoming[..], &mut outgoing)
+            .execute(&mut Summing)
+            .unwrap();
+        assert_eq!(unchunk(&outgoing), response(1, 10));
+    }
+
+    #[test]
+    fn test_upload_left_unread() {
+        // the chunks left by the first request are skipped
+        let incoming = chunks(&[
+            request(1, 2, 0),
+            chunk(1, &[1, 2, 3]),
+            chunk(1, &[4]),
+            chunk(1, &[]),
+            request(2, 100, 0),
+            chunk(2, &[5]),
+            chunk(2, &[]),
+        ]);
+        let mut outgoing = Vec::new();
+        Channel::new(&incoming[..], &mut outgoing)
+            .execute(&mut Summing)
+            .unwrap();
+        assert_eq!(
+            unchunk(&outgoing),
+            [response(1, 3), response(2, 5)].concat()
+        );
+    }
+
+    #[test]
+    fn test_call_upload() {
+        let incoming = chunks(&[response(1, 15)]);
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing);
+        let mut sink = client
+            .call_upload_with_chunk_size::<_, AddResponse>("Summing", AddRequest { a: 5, b: 0 }, 2)
+            .unwrap();
+        sink.write_all(&[1, 2, 3]).unwrap();
+        sink.write_all(&[4, 5]).unwrap();
+        assert_eq!(sink.finish().unwrap().0, 15);
+        let expected = [
+            request(1, 5, 0),
+            chunk(1, &[1, 2]),
+            chunk(1, &[3, 4]),
+            chunk(1, &[5]),
+            chunk(1, &[]),
+        ]
+        .concat();
+        assert_eq!(unchunk(&outgoing), expected);
+    }
+
+    #[test]
+    fn test_drop_upload() {
+        let incoming = chunks(&[response(1, 1), response(2, 3)]);
+        let mut outgoing = Vec::new();
+        let mut client = Channel::new(&incoming[..], &mut outgoing);
+        let mut sink = client
+            .call_upload::<_, AddResponse>("Summing", AddRequest { a: 5, b: 0 })
+            .unwrap();
+        sink.write_all(&[1]).unwrap();
+        drop(sink);
+        let resp: AddResponse = client.call("Adder.add", AddRequest { a: 1, b: 2 }).unwrap();
+        assert_eq!(resp.0, 3);
+        let expected = [
+            request(1, 5, 0),
+            chunk(1, &[1]),
+            chunk(1, &[]),
+            request(2, 1, 2),
+        ]
+        .concat();
+        assert_eq!(unchunk(&outgoing), expected);
+    }
+
+    #[test]
+    fn test_upload_over_pipes() {
+        let Spawned {
+            mut client, server, ..
+        } = spawn(Summing, true);
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let expected: u64 = data.iter().map(|b| *b as u64).sum();
+        let mut sink = client
+            .call_upload_with_chunk_size::<_, AddResponse>(
+                "Summing",
+                AddRequest { a: 1000, b: 0 },
+                7,
+            )
+            .unwrap();
+        sink.write_all(&data).unwrap();
+        assert_eq!(sink.finish().unwrap().0, expected);
+        // the server responds before the end of the upload
+        let mut sink = client
+            .call_upload_with_chunk_size::<_, AddResponse>("Summing", AddRequest { a: 2, b: 0 }, 7)
+            .unwrap();
+        sink.write_all(&data).unwrap();
+        assert_eq!(sink.finish().unwrap().0, 1);
+        let mut sink = client
+            .call_upload::<_, AddResponse>("Summing", AddRequest { a: 1000, b: 0 })
+            .unwrap();
+        sink.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(sink.finish().unwrap().0, 6);
+        drop(client);
+        server.join().unwrap().unwrap();
+    }
+}
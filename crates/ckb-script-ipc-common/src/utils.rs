@@ -1,7 +1,7 @@
 use crate::error::IpcError;
 use crate::io::Read;
 
-pub(crate) fn default_read_exact<R: Read + ?Sized>(
+pub(crate) fn default_read_exact<R: Read<Error = IpcError> + ?Sized>(
     this: &mut R,
     mut buf: &mut [u8],
 ) -> Result<(), IpcError> {
@@ -11,7 +11,7 @@ pub(crate) fn default_read_exact<R: Read + ?Sized>(
             Ok(n) => {
                 buf = &mut buf[n..];
             }
-            Err(_) => return Err(IpcError::ReadExactError),
+            Err(e) => return Err(e),
         }
     }
     if !buf.is_empty() {
@@ -23,7 +23,11 @@ pub(crate) fn default_read_exact<R: Read + ?Sized>(
 
 /// It is identical to `std::io::Read::read_exact`.
 /// Move this to here to relax the dependency of Error trait in `Read`.
-/// It returns `IpcError` only.
-pub fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), IpcError> {
+/// Errors of the reader are returned unchanged, reaching its end early fails
+/// with `IpcError::ReadExactError`.
+pub fn read_exact<R: Read<Error = IpcError>>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<(), IpcError> {
     default_read_exact(reader, buf)
 }
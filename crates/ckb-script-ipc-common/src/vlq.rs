@@ -1,9 +1,18 @@
 use alloc::vec::Vec;
 
 use crate::error::IpcError;
+/// Maximum length of a VLQ encoded `u64`.
+pub const MAX_VLQ_LEN: usize = 10;
+
 /// Encodes an integer using VLQ (Variable-Length Quantity) encoding.
-pub fn vlq_encode(mut value: u64) -> Vec<u8> {
+pub fn vlq_encode(value: u64) -> Vec<u8> {
     let mut buffer = Vec::new();
+    vlq_encode_into(value, &mut buffer);
+    buffer
+}
+
+/// Encodes an integer using VLQ encoding, appending it to `buffer`.
+pub fn vlq_encode_into(mut value: u64, buffer: &mut Vec<u8>) {
     loop {
         let mut byte = (value & 0x7F) as u8;
         value >>= 7;
@@ -15,7 +24,6 @@ pub fn vlq_encode(mut value: u64) -> Vec<u8> {
             break;
         }
     }
-    buffer
 }
 
 /// Decodes a VLQ (Variable-Length Quantity) encoded byte slice into an integer.
@@ -23,6 +31,10 @@ pub fn vlq_decode(bytes: &[u8]) -> Result<u64, IpcError> {
     let mut value = 0u64;
     let mut shift = 0;
     for &byte in bytes {
+        // the last byte only holds the highest bit
+        if shift == 63 && byte & 0x7F > 1 {
+            return Err(IpcError::DecodeVlqOverflow);
+        }
         value |= ((byte & 0x7F) as u64) << shift;
         if byte & 0x80 == 0 {
             return Ok(value);
@@ -81,6 +93,10 @@ mod tests {
             vlq_decode(&[128, 128, 128, 128, 128, 128, 128, 128, 128, 128]),
             Err(IpcError::DecodeVlqOverflow)
         ));
+        assert!(matches!(
+            vlq_decode(&[255, 255, 255, 255, 255, 255, 255, 255, 255, 2]),
+            Err(IpcError::DecodeVlqOverflow)
+        ));
         assert!(matches!(
             vlq_decode(&[128]),
             Err(IpcError::IncompleteVlqSeq)
@@ -0,0 +1,816 @@
+//!
+//! Procedural macros for ckb-script-ipc.
+//! The generated code is built on top of `ckb-script-ipc-common`, which must
+//! be a dependency of the crate using these macros, along with `serde`.
+//!
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, spanned::Spanned, Attribute, Error, FnArg, Ident, ItemTrait, Meta, Pat,
+    ReturnType, TraitItem, Type, Visibility,
+};
+
+/// Generates the server and client of an IPC service from a trait definition.
+///
+/// ```ignore
+/// #[ckb_script_ipc::service]
+/// pub trait World {
+///     fn hello(name: String) -> Result<String, u64>;
+/// }
+/// ```
+///
+/// will be expanded to:
+/// - trait `World`, with a `&self` receiver added to every method (unless
+///   one is given) and a `server` method to wrap an implementation,
+/// - `ServeWorld<S>`, implementing `ckb_script_ipc_common::ipc::Serve`,
+/// - `WorldRequest` and `WorldResponse`, with one variant per method,
+/// - `WorldClient`, calling the service over a `Channel`. Its methods return
+///   `Result<T, IpcError>`, where `T` is the return type of the method, so
+///   transport and protocol errors are kept apart from application errors,
+///   e.g. `Result<Result<String, u64>, IpcError>` for `hello`. Methods
+///   returning nothing are called with notifications: the client doesn't
+///   wait for the server, and errors on the server are not reported back.
+///
+/// Arguments and return types can be any type implementing `Serialize` and
+/// `Deserialize`, including application defined error types.
+///
+/// Methods marked with `#[peer]` get the other end of the channel, see
+/// `ckb_script_ipc_common::channel::Peer`, to call back the client, stream
+/// the response or read an upload while serving:
+///
+/// ```ignore
+/// #[ckb_script_ipc::service]
+/// pub trait Cells {
+///     #[peer]
+///     fn all(cursor: u64) -> bool;
+/// }
+///
+/// impl Cells for CellsServer {
+///     fn all<R, W>(&self, peer: &mut Peer<'_, R, W>, cursor: u64) -> bool
+///     where
+///         R: Read<Error = IpcError>,
+///         W: Write<Error = IpcError>,
+///     {
+///         peer.send_stream(self.cells.iter().skip(cursor as usize)).unwrap_or(false)
+///     }
+/// }
+/// ```
+///
+/// The client calls them like other methods, or through
+/// `CellsClient::channel_mut` to serve callbacks, receive a stream or send an
+/// upload, e.g. with `Channel::call_stream`.
+///
+/// Every method gets a method id, derived from its name, which is used on the
+/// wire to dispatch requests. Renaming a method changes its id, while adding
+/// or reordering methods doesn't. Method names must also give distinct
+/// variant names, e.g. `get_cell` and `get__cell` can't be used together.
+///
+/// Names used by the generated code are rejected: `new`, `channel_mut`,
+/// `call_batch` and `server` as method names, `_marker` as an argument name,
+/// and `peer` as an argument name of `#[peer]` methods.
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(Span::call_site(), "`service` does not take arguments")
+            .to_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(item as ItemTrait);
+    match Service::parse(&item) {
+        Ok(service) => service.expand().into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+// methods generated in the client or the trait
+const RESERVED_METHODS: &[&str] = &["new", "channel_mut", "call_batch", "server"];
+// fields generated in the arguments of a request
+const RESERVED_ARGS: &[&str] = &["_marker"];
+
+struct Service {
+    attrs: Vec<Attribute>,
+    vis: Visibility,
+    ident: Ident,
+    methods: Vec<Method>,
+}
+
+struct Method {
+    attrs: Vec<Attribute>,
+    ident: Ident,
+    id: u64,
+    variant: Ident,
+    receiver: TokenStream2,
+    args: Vec<(Ident, Type)>,
+    output: Type,
+    // returns nothing, so it's called with a notification
+    notification: bool,
+    // served with the other end of the channel
+    peer: bool,
+}
+
+impl Service {
+    fn parse(item: &ItemTrait) -> Result<Self, Error> {
+        if !item.generics.params.is_empty() || item.generics.where_clause.is_some() {
+            return Err(Error::new(
+                item.generics.span(),
+                "generic service traits are not supported",
+            ));
+        }
+        if !item.supertraits.is_empty() {
+            return Err(Error::new(
+                item.supertraits.span(),
+                "supertraits are not supported in service traits",
+            ));
+        }
+        let mut methods = vec![];
+        for trait_item in &item.items {
+            match trait_item {
+                TraitItem::Fn(f) => {
+                    if f.default.is_some() {
+                        return Err(Error::new(
+                            f.default.span(),
+                            "service methods cannot have a default implementation",
+                        ));
+                    }
+                    let method = Method::parse(f)?;
+                    if let Some(other) = methods.iter().find(|m: &&Method| m.id == method.id) {
+                        return Err(Error::new(
+                            method.ident.span(),
+                            format!(
+                                "method id of `{}` collides with `{}`, please rename one of them",
+                                method.ident, other.ident
+                            ),
+                        ));
+                    }
+                    if let Some(other) = methods.iter().find(|m| m.variant == method.variant) {
+                        return Err(Error::new(
+                            method.ident.span(),
+                            format!(
+                                "variant name `{}` of `{}` collides with `{}`, please rename one of them",
+                                method.variant, method.ident, other.ident
+                            ),
+                        ));
+                    }
+                    methods.push(method);
+                }
+                other => {
+                    return Err(Error::new(
+                        other.span(),
+                        "only methods are allowed in service traits",
+                    ))
+                }
+            }
+        }
+        if methods.is_empty() {
+            return Err(Error::new(
+                item.ident.span(),
+                "service traits must have at least one method",
+            ));
+        }
+        Ok(Self {
+            attrs: item.attrs.clone(),
+            vis: item.vis.clone(),
+            ident: item.ident.clone(),
+            methods,
+        })
+    }
+
+    fn expand(&self) -> TokenStream2 {
+        let attrs = &self.attrs;
+        let vis = &self.vis;
+        let ident = &self.ident;
+        let serve_ident = format_ident!("Serve{}", ident);
+        let request_ident = format_ident!("{}Request", ident);
+        let response_ident = format_ident!("{}Response", ident);
+        let client_ident = format_ident!("{}Client", ident);
+
+        let trait_methods = self.methods.iter().map(|m| {
+            let attrs = &m.attrs;
+            let ident = &m.ident;
+            let receiver = &m.receiver;
+            let args = m.args.iter().map(|(arg, ty)| quote! { #arg: #ty });
+            let output = &m.output;
+            if m.peer {
+                return quote! {
+                    #(#attrs)*
+                    fn #ident<R, W>(
+                        #receiver,
+                        peer: &mut ::ckb_script_ipc_common::channel::Peer<'_, R, W>,
+                        #(#args),*
+                    ) -> #output
+                    where
+                        R: ::ckb_script_ipc_common::io::Read<
+                            Error = ::ckb_script_ipc_common::error::IpcError,
+                        >,
+                        W: ::ckb_script_ipc_common::io::Write<
+                            Error = ::ckb_script_ipc_common::error::IpcError,
+                        >;
+                };
+            }
+            quote! {
+                #(#attrs)*
+                fn #ident(#receiver, #(#args),*) -> #output;
+            }
+        });
+        let request_variants = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            let args = m.args.iter().map(|(arg, ty)| quote! { #arg: #ty });
+            quote! { #variant { #(#args),* } }
+        });
+        let response_variants = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            let output = &m.output;
+            quote! { #variant(#output) }
+        });
+        let serve_arms = self.methods.iter().map(|m| {
+            let ident = &m.ident;
+            let variant = &m.variant;
+            let id = m.id;
+            let args: Vec<_> = m.args.iter().map(|(arg, _)| arg).collect();
+            if m.peer {
+                // can't be served without the other end
+                return quote! {
+                    #request_ident::#variant { .. } => Err(
+                        ::ckb_script_ipc_common::error::IpcError::UnexpectedRequest(#id),
+                    ),
+                };
+            }
+            quote! {
+                #request_ident::#variant { #(#args),* } => {
+                    let ret = self.service.#ident(#(#args),*);
+                    Ok(#response_ident::#variant(ret))
+                }
+            }
+        });
+        let serve_with_peer = if self.methods.iter().any(|m| m.peer) {
+            let arms = self.methods.iter().map(|m| {
+                let ident = &m.ident;
+                let variant = &m.variant;
+                let args: Vec<_> = m.args.iter().map(|(arg, _)| arg).collect();
+                let peer = if m.peer {
+                    quote! { __peer, }
+                } else {
+                    quote! {}
+                };
+                quote! {
+                    #request_ident::#variant { #(#args),* } => {
+                        let ret = self.service.#ident(#peer #(#args),*);
+                        Ok(#response_ident::#variant(ret))
+                    }
+                }
+            });
+            quote! {
+                fn serve_with_peer<R, W>(
+                    &mut self,
+                    req: Self::Req,
+                    __peer: &mut ::ckb_script_ipc_common::channel::Peer<'_, R, W>,
+                ) -> Result<Self::Resp, ::ckb_script_ipc_common::error::IpcError>
+                where
+                    R: ::ckb_script_ipc_common::io::Read<
+                        Error = ::ckb_script_ipc_common::error::IpcError,
+                    >,
+                    W: ::ckb_script_ipc_common::io::Write<
+                        Error = ::ckb_script_ipc_common::error::IpcError,
+                    >,
+                {
+                    match req {
+                        #(#arms)*
+                    }
+                }
+            }
+        } else {
+            quote! {}
+        };
+        let request_id_arms = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            let id = m.id;
+            quote! { #request_ident::#variant { .. } => #id, }
+        });
+        let request_encode_arms = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            let arg_names: Vec<_> = m.args.iter().map(|(arg, _)| arg).collect();
+            let args = m.args.iter().map(|(arg, ty)| quote! { #arg: &'a #ty });
+            quote! {
+                #request_ident::#variant { #(#arg_names),* } => {
+                    #[derive(::serde::Serialize)]
+                    struct Args<'a> {
+                        #(#args,)*
+                        #[serde(skip)]
+                        _marker: ::core::marker::PhantomData<&'a ()>,
+                    }
+                    ::ckb_script_ipc_common::ipc::encode(&Args {
+                        #(#arg_names,)*
+                        _marker: ::core::marker::PhantomData,
+                    })
+                }
+            }
+        });
+        let request_decode_arms = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            let id = m.id;
+            let arg_names: Vec<_> = m.args.iter().map(|(arg, _)| arg).collect();
+            let args = m.args.iter().map(|(arg, ty)| quote! { #arg: #ty });
+            quote! {
+                #id => {
+                    #[derive(::serde::Deserialize)]
+                    struct Args {
+                        #(#args),*
+                    }
+                    let Args { #(#arg_names),* } =
+                        ::ckb_script_ipc_common::ipc::decode(payload)?;
+                    Ok(#request_ident::#variant { #(#arg_names),* })
+                }
+            }
+        });
+        let response_id_arms = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            let id = m.id;
+            quote! { #response_ident::#variant(_) => #id, }
+        });
+        let response_encode_arms = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            quote! {
+                #response_ident::#variant(ret) => ::ckb_script_ipc_common::ipc::encode(ret),
+            }
+        });
+        let response_decode_arms = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            let id = m.id;
+            quote! {
+                #id => Ok(#response_ident::#variant(
+                    ::ckb_script_ipc_common::ipc::decode(payload)?,
+                )),
+            }
+        });
+        let method_name_arms = self.methods.iter().map(|m| {
+            let variant = &m.variant;
+            let method_name = format!("{}.{}", self.ident, m.ident);
+            quote! { #request_ident::#variant { .. } => Some(#method_name), }
+        });
+        let client_methods = self.methods.iter().map(|m| {
+            let attrs = &m.attrs;
+            let ident = &m.ident;
+            let variant = &m.variant;
+            let arg_names: Vec<_> = m.args.iter().map(|(arg, _)| arg).collect();
+            let args = m.args.iter().map(|(arg, ty)| quote! { #arg: #ty });
+            let output = &m.output;
+            let method_name = format!("{}.{}", self.ident, m.ident);
+            if m.notification {
+                return quote! {
+                    #(#attrs)*
+                    #vis fn #ident(
+                        &mut self,
+                        #(#args),*
+                    ) -> Result<(), ::ckb_script_ipc_common::error::IpcError> {
+                        let request = #request_ident::#variant { #(#arg_names),* };
+                        self.channel.send_notification(request)
+                    }
+                };
+            }
+            let mismatch_arm = if self.methods.len() > 1 {
+                quote! {
+                    Ok(_) => Err(::ckb_script_ipc_common::error::IpcError::UnexpectedResponse),
+                }
+            } else {
+                quote! {}
+            };
+            quote! {
+                #(#attrs)*
+                #vis fn #ident(
+                    &mut self,
+                    #(#args),*
+                ) -> Result<#output, ::ckb_script_ipc_common::error::IpcError> {
+                    let request = #request_ident::#variant { #(#arg_names),* };
+                    let resp = self
+                        .channel
+                        .call::<_, #response_ident>(#method_name, request);
+                    match resp {
+                        Ok(#response_ident::#variant(ret)) => Ok(ret),
+                        #mismatch_arm
+                        Err(e) => Err(e),
+                    }
+                }
+            }
+        });
+
+        quote! {
+            #(#attrs)*
+            #vis trait #ident: Sized {
+                #(#trait_methods)*
+
+                /// Wraps this implementation into a server, ready to be executed on a channel.
+                fn server(self) -> #serve_ident<Self> {
+                    #serve_ident { service: self }
+                }
+            }
+
+            #vis struct #serve_ident<S> {
+                service: S,
+            }
+
+            impl<S> ::ckb_script_ipc_common::ipc::Serve for #serve_ident<S>
+            where
+                S: #ident,
+            {
+                type Req = #request_ident;
+                type Resp = #response_ident;
+                fn serve(
+                    &mut self,
+                    req: Self::Req,
+                ) -> Result<Self::Resp, ::ckb_script_ipc_common::error::IpcError> {
+                    match req {
+                        #(#serve_arms)*
+                    }
+                }
+                #serve_with_peer
+                fn method(&self, request: &Self::Req) -> Option<&'static str> {
+                    match request {
+                        #(#method_name_arms)*
+                    }
+                }
+            }
+
+            #vis enum #request_ident {
+                #(#request_variants),*
+            }
+
+            impl ::ckb_script_ipc_common::ipc::Message for #request_ident {
+                fn method_id(&self) -> u64 {
+                    match self {
+                        #(#request_id_arms)*
+                    }
+                }
+                fn encode(
+                    &self,
+                ) -> Result<
+                    ::ckb_script_ipc_common::ipc::Vec<u8>,
+                    ::ckb_script_ipc_common::error::IpcError,
+                > {
+                    match self {
+                        #(#request_encode_arms)*
+                    }
+                }
+                fn decode(
+                    method_id: u64,
+                    payload: &[u8],
+                ) -> Result<Self, ::ckb_script_ipc_common::error::IpcError> {
+                    match method_id {
+                        #(#request_decode_arms)*
+                        _ => Err(::ckb_script_ipc_common::error::IpcError::UnknownMethod(method_id)),
+                    }
+                }
+            }
+
+            #vis enum #response_ident {
+                #(#response_variants),*
+            }
+
+            impl ::ckb_script_ipc_common::ipc::Message for #response_ident {
+                fn method_id(&self) -> u64 {
+                    match self {
+                        #(#response_id_arms)*
+                    }
+                }
+                fn encode(
+                    &self,
+                ) -> Result<
+                    ::ckb_script_ipc_common::ipc::Vec<u8>,
+                    ::ckb_script_ipc_common::error::IpcError,
+                > {
+                    match self {
+                        #(#response_encode_arms)*
+                    }
+                }
+                fn decode(
+                    method_id: u64,
+                    payload: &[u8],
+                ) -> Result<Self, ::ckb_script_ipc_common::error::IpcError> {
+                    match method_id {
+                        #(#response_decode_arms)*
+                        _ => Err(::ckb_script_ipc_common::error::IpcError::UnknownMethod(method_id)),
+                    }
+                }
+            }
+
+            #vis struct #client_ident<
+                R = ::ckb_script_ipc_common::pipe::Pipe,
+                W = ::ckb_script_ipc_common::pipe::Pipe,
+            > {
+                channel: ::ckb_script_ipc_common::channel::Channel<R, W>,
+            }
+
+            impl<R, W> From<::ckb_script_ipc_common::channel::Channel<R, W>> for #client_ident<R, W> {
+                fn from(channel: ::ckb_script_ipc_common::channel::Channel<R, W>) -> Self {
+                    Self { channel }
+                }
+            }
+
+            impl<R, W> #client_ident<R, W>
+            where
+                R: ::ckb_script_ipc_common::io::Read<
+                    Error = ::ckb_script_ipc_common::error::IpcError,
+                >,
+                W: ::ckb_script_ipc_common::io::Write<
+                    Error = ::ckb_script_ipc_common::error::IpcError,
+                >,
+            {
+                #vis fn new(reader: R, writer: W) -> Self {
+                    Self {
+                        channel: ::ckb_script_ipc_common::channel::Channel::new(reader, writer),
+                    }
+                }
+
+                /// Returns the channel, to call methods with callbacks, streams or
+                /// uploads, e.g. `Channel::call_stream`.
+                #vis fn channel_mut(
+                    &mut self,
+                ) -> &mut ::ckb_script_ipc_common::channel::Channel<R, W> {
+                    &mut self.channel
+                }
+
+                /// Sends all the requests at once, then receives their responses
+                /// in order, see `Channel::call_batch`.
+                #vis fn call_batch<I: IntoIterator<Item = #request_ident>>(
+                    &mut self,
+                    requests: I,
+                ) -> Result<
+                    ::ckb_script_ipc_common::ipc::Vec<
+                        Result<#response_ident, ::ckb_script_ipc_common::error::IpcError>,
+                    >,
+                    ::ckb_script_ipc_common::error::IpcError,
+                > {
+                    self.channel.call_batch(requests)
+                }
+
+                #(#client_methods)*
+            }
+        }
+    }
+}
+
+impl Method {
+    fn parse(f: &syn::TraitItemFn) -> Result<Self, Error> {
+        let sig = &f.sig;
+        if !sig.generics.params.is_empty() || sig.generics.where_clause.is_some() {
+            return Err(Error::new(
+                sig.generics.span(),
+                "generic service methods are not supported",
+            ));
+        }
+        if sig.asyncness.is_some() || sig.unsafety.is_some() || sig.variadic.is_some() {
+            return Err(Error::new(
+                sig.span(),
+                "service methods must be plain functions",
+            ));
+        }
+        if is_reserved(&sig.ident, RESERVED_METHODS) {
+            return Err(Error::new(
+                sig.ident.span(),
+                format!(
+                    "method name `{}` is used by the generated code, please rename it",
+                    sig.ident
+                ),
+            ));
+        }
+        let mut receiver = quote! { &self };
+        let mut args = vec![];
+        for (i, input) in sig.inputs.iter().enumerate() {
+            match input {
+                FnArg::Receiver(r) => {
+                    if i != 0 || r.reference.is_none() {
+                        return Err(Error::new(
+                            r.span(),
+                            "only `&self` and `&mut self` receivers are supported",
+                        ));
+                    }
+                    receiver = quote! { #r };
+                }
+                FnArg::Typed(pat_type) => match &*pat_type.pat {
+                    Pat::Ident(pat_ident) if pat_ident.subpat.is_none() => {
+                        args.push((pat_ident.ident.clone(), (*pat_type.ty).clone()));
+                    }
+                    pat => {
+                        return Err(Error::new(
+                            pat.span(),
+                            "service method arguments must be plain identifiers",
+                        ))
+                    }
+                },
+            }
+        }
+        let output = match &sig.output {
+            ReturnType::Default => syn::parse_quote! { () },
+            ReturnType::Type(_, ty) => (**ty).clone(),
+        };
+        let notification = matches!(&output, Type::Tuple(t) if t.elems.is_empty());
+        let mut attrs = vec![];
+        let mut peer = false;
+        for attr in &f.attrs {
+            if !attr.path().is_ident("peer") {
+                attrs.push(attr.clone());
+            } else if matches!(attr.meta, Meta::Path(_)) {
+                peer = true;
+            } else {
+                return Err(Error::new(attr.span(), "`peer` does not take arguments"));
+            }
+        }
+        for (arg, _) in &args {
+            if is_reserved(arg, RESERVED_ARGS) || (peer && arg == "peer") {
+                return Err(Error::new(
+                    arg.span(),
+                    format!(
+                        "argument name `{}` is used by the generated code, please rename it",
+                        arg
+                    ),
+                ));
+            }
+        }
+        Ok(Self {
+            attrs,
+            ident: sig.ident.clone(),
+            id: method_id(&sig.ident),
+            variant: to_camel_case(&sig.ident),
+            receiver,
+            args,
+            output,
+            notification,
+            peer,
+        })
+    }
+}
+
+fn is_reserved(ident: &Ident, names: &[&str]) -> bool {
+    names.contains(&ident.to_string().trim_start_matches("r#"))
+}
+
+/// Converts a method name like `get_cell` into a variant name like `GetCell`.
+fn to_camel_case(ident: &Ident) -> Ident {
+    let name = ident.to_string();
+    let camel: String = name
+        .trim_start_matches("r#")
+        .split('_')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let mut chars = s.chars();
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars).collect::<String>(),
+                None => String::new(),
+            }
+        })
+        .collect();
+    Ident::new(&camel, ident.span())
+}
+
+/// Derives the method id from a method name, using 32-bit FNV-1a. The id is
+/// kept in 32 bits so it takes at most 5 bytes in VLQ encoding.
+fn method_id(ident: &Ident) -> u64 {
+    let name = ident.to_string();
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in name.trim_start_matches("r#").bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::{parse_quote, Item};
+
+    fn parse_error(item: ItemTrait) -> String {
+        match Service::parse(&item) {
+            Ok(_) => panic!("expect error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let service = Service::parse(&parse_quote! {
+            trait Cells {
+                fn get_cell(index: u64) -> Vec<u8>;
+                fn r#type(&mut self);
+            }
+        })
+        .unwrap();
+        let variants: Vec<String> = service
+            .methods
+            .iter()
+            .map(|m| m.variant.to_string())
+            .collect();
+        assert_eq!(variants, ["GetCell", "Type"]);
+        assert_eq!(service.methods[0].id, method_id(&parse_quote!(get_cell)));
+        assert_eq!(service.methods[1].id, method_id(&parse_quote!(r#type)));
+        assert!(!service.methods[0].notification);
+        assert!(service.methods[1].notification);
+
+        let service = Service::parse(&parse_quote! {
+            trait Cells {
+                /// doc
+                #[peer]
+                fn all(cursor: u64) -> bool;
+            }
+        })
+        .unwrap();
+        assert!(service.methods[0].peer);
+        assert_eq!(service.methods[0].attrs.len(), 1);
+    }
+
+    #[test]
+    fn test_variant_collision() {
+        let e = parse_error(parse_quote! {
+            trait Cells {
+                fn get_cell(index: u64) -> Vec<u8>;
+                fn get__cell(index: u64) -> Vec<u8>;
+            }
+        });
+        assert!(e.contains("`GetCell` of `get__cell` collides with `get_cell`"));
+    }
+
+    #[test]
+    fn test_reserved_names() {
+        for method in ["new", "channel_mut", "call_batch", "server"] {
+            let ident = Ident::new(method, Span::call_site());
+            let e = parse_error(parse_quote! {
+                trait Cells {
+                    fn #ident(index: u64) -> Vec<u8>;
+                }
+            });
+            assert!(e.contains(&format!("method name `{}` is used", method)));
+        }
+        let e = parse_error(parse_quote! {
+            trait Cells {
+                fn get_cell(_marker: u64) -> Vec<u8>;
+            }
+        });
+        assert!(e.contains("argument name `_marker` is used"));
+        let e = parse_error(parse_quote! {
+            trait Cells {
+                #[peer]
+                fn all(peer: u64) -> bool;
+            }
+        });
+        assert!(e.contains("argument name `peer` is used"));
+        // only `#[peer]` methods get the other end as `peer`
+        Service::parse(&parse_quote! {
+            trait Cells {
+                fn all(peer: u64) -> bool;
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let e = parse_error(parse_quote! {
+            trait Cells {}
+        });
+        assert!(e.contains("at least one method"));
+        let e = parse_error(parse_quote! {
+            trait Cells {
+                fn get_cell(self) -> Vec<u8>;
+            }
+        });
+        assert!(e.contains("receivers"));
+        let e = parse_error(parse_quote! {
+            trait Cells {
+                #[peer(all)]
+                fn all(cursor: u64) -> bool;
+            }
+        });
+        assert!(e.contains("`peer` does not take arguments"));
+    }
+
+    #[test]
+    fn test_expand() {
+        let service = Service::parse(&parse_quote! {
+            pub trait World {
+                fn hello(name: String) -> String;
+            }
+        })
+        .unwrap();
+        let file: syn::File = syn::parse2(service.expand()).unwrap();
+        let items: Vec<String> = file
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Trait(item) => Some(item.ident.to_string()),
+                Item::Struct(item) => Some(item.ident.to_string()),
+                Item::Enum(item) => Some(item.ident.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            items,
+            [
+                "World",
+                "ServeWorld",
+                "WorldRequest",
+                "WorldResponse",
+                "WorldClient"
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use ckb_script_ipc_common::channel::Peer;
+use ckb_script_ipc_common::error::IpcError;
+use ckb_script_ipc_common::io::{Read, Write};
+use ckb_script_ipc_common::ipc::{Message, Serve};
+use ckb_script_ipc_common::loopback::loopback;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum CellError {
+    NotFound(u64),
+}
+
+#[ckb_script_ipc::service]
+pub trait Cells {
+    /// Returns the data of a cell.
+    fn get_cell(index: u64, source: String) -> Result<Vec<u8>, CellError>;
+    fn set_base(&mut self, base: u64);
+    fn count(&self) -> u64;
+}
+
+struct CellsServer {
+    base: u64,
+    count: u64,
+}
+
+impl Cells for CellsServer {
+    fn get_cell(&self, index: u64, source: String) -> Result<Vec<u8>, CellError> {
+        if index < self.count && source == "input" {
+            Ok(vec![(self.base + index) as u8])
+        } else {
+            Err(CellError::NotFound(index))
+        }
+    }
+    fn set_base(&mut self, base: u64) {
+        self.base = base;
+    }
+    fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[test]
+fn test_request_round_trip() {
+    let req = CellsRequest::GetCell {
+        index: 5,
+        source: "input".into(),
+    };
+    let method_id = req.method_id();
+    let payload = req.encode().unwrap();
+    match CellsRequest::decode(method_id, &payload).unwrap() {
+        CellsRequest::GetCell { index, source } => {
+            assert_eq!(index, 5);
+            assert_eq!(source, "input");
+        }
+        _ => panic!("expect GetCell"),
+    }
+    assert_ne!(CellsRequest::Count {}.method_id(), method_id);
+    assert!(matches!(
+        CellsRequest::decode(0, &payload),
+        Err(IpcError::UnknownMethod(0))
+    ));
+}
+
+#[test]
+fn test_response_round_trip() {
+    let resp = CellsResponse::GetCell(Err(CellError::NotFound(3)));
+    let method_id = resp.method_id();
+    let payload = resp.encode().unwrap();
+    assert!(matches!(
+        CellsResponse::decode(method_id, &payload).unwrap(),
+        CellsResponse::GetCell(Err(CellError::NotFound(3)))
+    ));
+    assert_eq!(
+        CellsRequest::GetCell {
+            index: 0,
+            source: String::new()
+        }
+        .method_id(),
+        method_id
+    );
+}
+
+#[test]
+fn test_client() {
+    let (reader, writer) = loopback(CellsServer { base: 10, count: 2 }.server());
+    let mut client = CellsClient::new(reader, writer);
+    assert_eq!(client.get_cell(1, "input".into()).unwrap(), Ok(vec![11]));
+    assert_eq!(
+        client.get_cell(2, "input".into()).unwrap(),
+        Err(CellError::NotFound(2))
+    );
+    // a notification, served before the next request
+    client.set_base(20).unwrap();
+    assert_eq!(client.get_cell(0, "input".into()).unwrap(), Ok(vec![20]));
+    assert_eq!(client.count().unwrap(), 2);
+
+    let resps = client
+        .call_batch([
+            CellsRequest::Count {},
+            CellsRequest::GetCell {
+                index: 1,
+                source: "input".into(),
+            },
+        ])
+        .unwrap();
+    assert!(matches!(resps[0], Ok(CellsResponse::Count(2))));
+    assert!(matches!(&resps[1], Ok(CellsResponse::GetCell(Ok(data))) if data == &[21]));
+}
+
+#[ckb_script_ipc::service]
+pub trait Numbers {
+    /// Streams `start..end`, returns whether all of them are sent.
+    #[peer]
+    fn range(start: u64, end: u64) -> bool;
+    fn double(n: u64) -> u64;
+}
+
+struct NumbersServer;
+
+impl Numbers for NumbersServer {
+    fn range<R, W>(&self, peer: &mut Peer<'_, R, W>, start: u64, end: u64) -> bool
+    where
+        R: Read<Error = IpcError>,
+        W: Write<Error = IpcError>,
+    {
+        peer.send_stream(start..end).unwrap_or(false)
+    }
+    fn double(&self, n: u64) -> u64 {
+        n * 2
+    }
+}
+
+#[test]
+fn test_peer() {
+    let (reader, writer) = loopback(NumbersServer.server());
+    let mut client = NumbersClient::new(reader, writer);
+    let req = NumbersRequest::Range { start: 1, end: 4 };
+    let mut stream = client
+        .channel_mut()
+        .call_stream::<_, u64, NumbersResponse>("Numbers.range", req)
+        .unwrap();
+    let items: Result<Vec<u64>, IpcError> = (&mut stream).collect();
+    assert_eq!(items.unwrap(), [1, 2, 3]);
+    assert!(matches!(
+        stream.finish().unwrap(),
+        NumbersResponse::Range(true)
+    ));
+    assert_eq!(client.double(2).unwrap(), 4);
+
+    // the method can't be served without the other end
+    let req = NumbersRequest::Range { start: 1, end: 4 };
+    let method_id = req.method_id();
+    assert!(matches!(
+        NumbersServer.server().serve(req),
+        Err(IpcError::UnexpectedRequest(id)) if id == method_id
+    ));
+}
@@ -1,21 +1,31 @@
 use crate::Loader;
-use ckb_testtool::ckb_types::{bytes::Bytes, core::TransactionBuilder, packed::*, prelude::*};
+use ckb_testtool::ckb_error::{Error, ErrorKind};
+use ckb_testtool::ckb_script::{ScriptError, TransactionScriptError};
+use ckb_testtool::ckb_types::{
+    bytes::Bytes,
+    core::{Cycle, TransactionBuilder},
+    packed::*,
+    prelude::*,
+};
 use ckb_testtool::context::Context;
 
 // Include your tests here
 // See https://github.com/xxuejie/ckb-native-build-sample/blob/main/tests/src/tests.rs for more examples
-// generated unit test for contract ckb-script-ipc-demo
-#[test]
-fn test_ckb_script_ipc_demo() {
+// Runs the demo contract in a transaction, with the lock script args `args`,
+// and returns consumed cycles.
+fn run_ckb_script_ipc_demo(args: Bytes) -> u64 {
+    verify_ckb_script_ipc_demo(args).expect("pass verification")
+}
+
+// Same as `run_ckb_script_ipc_demo`, returning the verification result.
+fn verify_ckb_script_ipc_demo(args: Bytes) -> Result<Cycle, Error> {
     // deploy contract
     let mut context = Context::default();
     let contract_bin: Bytes = Loader::default().load_binary("ckb-script-ipc-demo");
     let out_point = context.deploy_cell(contract_bin);
 
     // prepare scripts
-    let lock_script = context
-        .build_script(&out_point, Bytes::from(vec![42]))
-        .expect("script");
+    let lock_script = context.build_script(&out_point, args).expect("script");
 
     // prepare cells
     let input_out_point = context.create_cell(
@@ -50,8 +60,58 @@ fn test_ckb_script_ipc_demo() {
     let tx = context.complete_tx(tx);
 
     // run
-    let cycles = context
-        .verify_tx(&tx, 10_000_000)
-        .expect("pass verification");
+    context.verify_tx(&tx, 10_000_000)
+}
+
+// generated unit test for contract ckb-script-ipc-demo
+#[test]
+fn test_ckb_script_ipc_demo() {
+    let cycles = run_ckb_script_ipc_demo(Bytes::from(vec![42]));
+    println!("consume cycles: {}", cycles);
+}
+
+#[test]
+fn test_buffered_read_cycles() {
+    // first byte of args being 0 makes the demo read pipes without buffering
+    let unbuffered = run_ckb_script_ipc_demo(Bytes::from(vec![0]));
+    let buffered = run_ckb_script_ipc_demo(Bytes::from(vec![42]));
+    println!(
+        "consume cycles: unbuffered {}, buffered {}, saved {}",
+        unbuffered,
+        buffered,
+        unbuffered - buffered
+    );
+    assert!(buffered < unbuffered);
+}
+
+// The second byte of args makes the demo pipeline echo requests, sent at
+// once, while the server sends large responses.
+#[test]
+fn test_pipelined_requests() {
+    let cycles = run_ckb_script_ipc_demo(Bytes::from(vec![42, 1]));
     println!("consume cycles: {}", cycles);
 }
+
+#[test]
+fn test_pipelined_requests_without_flow_control() {
+    let err = verify_ckb_script_ipc_demo(Bytes::from(vec![42, 2])).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Script);
+    let err = err
+        .downcast_ref::<TransactionScriptError>()
+        .expect("transaction script error");
+    // Both scripts block writing to each other, so no VM is left to run. The
+    // VM scheduler detects it and fails with this error, which is neither a
+    // script exiting with an error code nor running out of cycles.
+    let cause = err.script_error();
+    assert!(
+        matches!(
+            cause,
+            ScriptError::VMInternalError(e)
+                if format!("{:?}", e).contains("A deadlock situation has been reached")
+        ),
+        "unexpected error: {}",
+        err
+    );
+    // in the lock script of the input, which spawns the server
+    assert_eq!(err, &cause.clone().input_lock_script(0));
+}